@@ -1,5 +1,3 @@
-use tonic_build;
-
 fn main() {
     tonic_build::configure()
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
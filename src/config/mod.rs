@@ -0,0 +1,713 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+use anyhow::{Context, Result};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub recommend: RecommendConfig,
+    pub similar: SimilarConfig,
+    pub search: SearchConfig,
+    pub query: QueryConfig,
+    pub suggest: SuggestConfig,
+    pub search_log: SearchLogConfig,
+    pub hotspot: HotspotConfig,
+    pub event: EventConfig,
+    pub llm: LlmConfig,
+    pub moderation: ModerationConfig,
+    pub rate_limit: RateLimitConfig,
+    pub validation: ValidationConfig,
+    pub telemetry: TelemetryConfig,
+    pub lifecycle: LifecycleConfig,
+}
+
+impl Config {
+    /// Registered for validation or configured with its own rate limits.
+    pub fn knows_namespace(&self, namespace: &str) -> bool {
+        self.validation.namespaces.iter().any(|ns| ns == namespace)
+            || self.rate_limit.namespaces.contains_key(namespace)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecommendConfig {
+    /// Used when a request has no `page_size`, the largest one allowed is `validation.max_page_size`.
+    pub default_page_size: usize,
+    /// Skip items already shown to the user per a redis bloom filter, on top of the cursor's seen set.
+    pub impression_dedup: bool,
+}
+
+impl Default for RecommendConfig {
+    fn default() -> Self {
+        RecommendConfig {
+            default_page_size: 20,
+            impression_dedup: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimilarConfig {
+    pub default_limit: usize,
+    pub max_limit: usize,
+    /// Seconds to keep unfiltered results per item in redis, 0 disables the cache.
+    pub cache_ttl_secs: u64,
+    /// HNSW search breadth, raised to the search limit since milvus rejects a smaller one.
+    pub ef: usize,
+}
+
+impl Default for SimilarConfig {
+    fn default() -> Self {
+        SimilarConfig {
+            default_limit: 20,
+            max_limit: 100,
+            cache_ttl_secs: 600,
+            ef: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchConfig {
+    pub default_profile: SearchProfile,
+    pub profiles: HashMap<String, SearchProfile>,
+    pub bounds: SearchBounds,
+    /// Seconds a fused result window is kept in redis for paging.
+    pub window_cache_ttl_secs: u64,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            default_profile: SearchProfile::default(),
+            profiles: HashMap::new(),
+            bounds: SearchBounds::default(),
+            window_cache_ttl_secs: 60,
+        }
+    }
+}
+
+impl SearchConfig {
+    pub fn profile(&self, namespace: &str) -> &SearchProfile {
+        self.profiles.get(namespace).unwrap_or(&self.default_profile)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum Rerank {
+    Rrf { k: u32 },
+    /// One weight per leg, dense first then sparse.
+    Weighted { weights: Vec<f64> },
+}
+
+/// How a namespace fuses its dense `multi_embedding` and sparse `title_embeddings` legs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchProfile {
+    pub rerank: Rerank,
+    /// Candidates each leg contributes to the fused window.
+    pub dense_limit: usize,
+    pub sparse_limit: usize,
+    /// Fused results fetched once per query and paged through.
+    pub window_size: usize,
+    pub page_size: usize,
+    /// HNSW search breadth of the dense leg, raised to `dense_limit` since milvus rejects a smaller one.
+    pub ef: usize,
+    pub drop_ratio_search: f64,
+}
+
+impl Default for SearchProfile {
+    fn default() -> Self {
+        SearchProfile {
+            rerank: Rerank::Rrf { k: 60 },
+            dense_limit: 100,
+            sparse_limit: 100,
+            window_size: 100,
+            page_size: 20,
+            ef: 100,
+            drop_ratio_search: 0.2,
+        }
+    }
+}
+
+/// Upper limits a request may override a profile with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchBounds {
+    pub max_rrf_k: u32,
+    pub max_leg_limit: usize,
+    pub max_page_size: usize,
+    pub max_ef: usize,
+    pub max_drop_ratio_search: f64,
+}
+
+impl Default for SearchBounds {
+    fn default() -> Self {
+        SearchBounds {
+            max_rrf_k: 16384,
+            max_leg_limit: 200,
+            max_page_size: 100,
+            max_ef: 512,
+            max_drop_ratio_search: 0.9,
+        }
+    }
+}
+
+/// Dictionary files for search query processing, each step is skipped when its file is not set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryConfig {
+    /// OpenCC style `traditional<TAB>simplified` character table.
+    pub t2s_path: Option<String>,
+    /// `misspelling<TAB>correction` per line.
+    pub corrections_path: Option<String>,
+    /// Comma separated synonym group per line.
+    pub synonyms_path: Option<String>,
+    pub max_synonyms: usize,
+    pub llm_rewrite: bool,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        QueryConfig {
+            t2s_path: None,
+            corrections_path: None,
+            synonyms_path: None,
+            max_synonyms: 3,
+            llm_rewrite: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuggestConfig {
+    pub default_limit: usize,
+    pub max_limit: usize,
+    /// Queries are indexed under each of their first `max_prefix_len` character prefixes.
+    pub max_prefix_len: usize,
+    /// Most popular queries kept per prefix.
+    pub max_entries_per_prefix: usize,
+    /// Extra entries kept per prefix beyond `max_entries_per_prefix`, so a new query can
+    /// build up a score before it competes for a place.
+    pub headroom_per_prefix: usize,
+    /// Hours after which a past search counts half as much as a new one.
+    pub half_life_hours: f64,
+}
+
+impl Default for SuggestConfig {
+    fn default() -> Self {
+        SuggestConfig {
+            default_limit: 10,
+            max_limit: 20,
+            max_prefix_len: 10,
+            max_entries_per_prefix: 50,
+            headroom_per_prefix: 50,
+            half_life_hours: 168.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchLogSink {
+    None,
+    Kafka,
+    File,
+}
+
+/// Where search impressions and clicks are written for CTR and ranking training data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchLogConfig {
+    pub sink: SearchLogSink,
+    pub topic: String,
+    /// Directory of the daily rolled `search-YYYY-MM-DD.log` files.
+    pub dir: String,
+}
+
+impl Default for SearchLogConfig {
+    fn default() -> Self {
+        SearchLogConfig {
+            sink: SearchLogSink::File,
+            topic: "search-log".to_string(),
+            dir: "logs".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotspotConfig {
+    pub default_detector: DetectorConfig,
+    pub detectors: HashMap<String, DetectorConfig>,
+    /// How long a replica's claim on announcing a new hotspot holds.
+    pub announce_ttl_secs: u64,
+    /// Names this replica's snapshot, defaults to `$HOSTNAME`.
+    pub replica_id: Option<String>,
+    /// Snapshots not refreshed for this long are dropped instead of restored.
+    pub snapshot_ttl_secs: i64,
+    /// Also feed events to the Flink top-k job over kafka and store its output in `topk:{namespace}`.
+    pub flink_topk: bool,
+}
+
+impl Default for HotspotConfig {
+    fn default() -> Self {
+        HotspotConfig {
+            default_detector: DetectorConfig::default(),
+            detectors: HashMap::new(),
+            announce_ttl_secs: 3600,
+            replica_id: None,
+            snapshot_ttl_secs: 86400,
+            flink_topk: false,
+        }
+    }
+}
+
+impl HotspotConfig {
+    pub fn detector(&self, namespace: &str) -> &DetectorConfig {
+        self.detectors.get(namespace).unwrap_or(&self.default_detector)
+    }
+
+    pub fn replica_id(&self) -> String {
+        self.replica_id.clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "default".to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HotspotCounting {
+    /// Hits seen by this replica only.
+    Local,
+    /// Hits across all replicas, counted in bucketed redis counters.
+    Redis,
+}
+
+/// A key is hot once it is hit more than `threshold` times within `window_secs` and that is at least
+/// `min_trend_score` deviations above its long-term rate. Hot keys then cool off by `score_half_life_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetectorConfig {
+    pub window_secs: i64,
+    /// Sub-windows the window slides by.
+    pub buckets: usize,
+    pub threshold: u32,
+    /// Count-Min Sketch counters per row and number of rows.
+    pub width: usize,
+    pub depth: usize,
+    /// Candidate keys tracked per namespace, only these can become hotspots.
+    pub top_k: usize,
+    pub baseline_half_life_secs: i64,
+    pub min_trend_score: f64,
+    pub score_half_life_secs: i64,
+    /// Hotspots whose decayed score drops below this are removed.
+    pub cooloff_score: f64,
+    pub counting: HotspotCounting,
+}
+
+impl DetectorConfig {
+    pub fn bucket_secs(&self) -> i64 {
+        (self.window_secs / self.buckets.max(1) as i64).max(1)
+    }
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig {
+            window_secs: 600,
+            buckets: 10,
+            threshold: 2,
+            width: 2048,
+            depth: 4,
+            top_k: 200,
+            baseline_half_life_secs: 86400,
+            min_trend_score: 2.0,
+            score_half_life_secs: 1800,
+            cooloff_score: 0.5,
+            counting: HotspotCounting::Redis,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EventConfig {
+    pub default_cluster: ClusterConfig,
+    /// Keyed by hotspot namespace, e.g. `{namespace}_search`.
+    pub clusters: HashMap<String, ClusterConfig>,
+    pub merge_interval_secs: u64,
+    /// New events and hits are buffered and written at this interval.
+    pub flush_interval_secs: u64,
+    /// Buffered new events per collection that trigger an early flush.
+    pub max_pending: usize,
+    pub compaction_interval_secs: u64,
+    /// Seconds a compaction run keeps deleting idle events, the rest waits for the next run.
+    pub compaction_budget_secs: u64,
+    pub prefilter: PrefilterConfig,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        EventConfig {
+            default_cluster: ClusterConfig::default(),
+            clusters: HashMap::new(),
+            merge_interval_secs: 3600,
+            flush_interval_secs: 10,
+            max_pending: 256,
+            compaction_interval_secs: 86400,
+            compaction_budget_secs: 300,
+            prefilter: PrefilterConfig::default(),
+        }
+    }
+}
+
+impl EventConfig {
+    pub fn cluster(&self, namespace: &str) -> &ClusterConfig {
+        self.clusters.get(namespace).unwrap_or(&self.default_cluster)
+    }
+}
+
+/// Cheap checks that drop junk keywords before any model call or event insertion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrefilterConfig {
+    pub min_chars: usize,
+    pub max_chars: usize,
+    /// Longest run of one repeated character, e.g. `aaaaaa`.
+    pub max_repeat: usize,
+    /// Keywords equal to a line of this file are dropped.
+    pub stopwords_path: Option<String>,
+    /// Keywords and events containing a line of this file are dropped.
+    pub blocklist_path: Option<String>,
+}
+
+impl Default for PrefilterConfig {
+    fn default() -> Self {
+        PrefilterConfig {
+            min_chars: 2,
+            max_chars: 30,
+            max_repeat: 4,
+            stopwords_path: None,
+            blocklist_path: None,
+        }
+    }
+}
+
+/// How distilled events are grouped into one canonical event per topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    pub collection: String,
+    /// Neighbors considered when matching a new event.
+    pub top_k: i32,
+    /// Inner product at which a new event joins an existing cluster.
+    pub match_threshold: f32,
+    /// Inner product at which two stored events are merged by the background task.
+    pub merge_threshold: f32,
+    /// Events scanned per merge run.
+    pub merge_batch: usize,
+    /// Events not seen for this many days are deleted, 0 keeps them forever.
+    pub max_idle_days: i64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            collection: "event".to_string(),
+            top_k: 5,
+            match_threshold: 0.8,
+            merge_threshold: 0.9,
+            merge_batch: 1000,
+            max_idle_days: 30,
+        }
+    }
+}
+
+/// Bounds every rpc input is checked against before any handler runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    /// Registered namespaces, empty accepts any well-formed namespace.
+    pub namespaces: Vec<String>,
+    pub max_keyword_chars: usize,
+    pub max_prefix_chars: usize,
+    pub max_page: i64,
+    pub max_page_size: i32,
+    pub max_exclude_ids: usize,
+    pub max_cursor_len: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            namespaces: Vec::new(),
+            max_keyword_chars: 100,
+            max_prefix_chars: 50,
+            max_page: 100,
+            max_page_size: 100,
+            max_exclude_ids: 1000,
+            max_cursor_len: 16384,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub default_limits: RpcLimits,
+    /// Keyed by request namespace. Namespaces neither listed here nor registered share the limits of `other`.
+    pub namespaces: HashMap<String, RpcLimits>,
+    /// Bearer token per caller id, a caller is only limited by its `x-caller-id` when it sends the token.
+    pub caller_tokens: HashMap<String, String>,
+    /// Callers tracked at once and how long an idle caller's bucket is kept.
+    pub max_callers: u64,
+    pub caller_idle_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            default_limits: RpcLimits::default(),
+            namespaces: HashMap::new(),
+            caller_tokens: HashMap::new(),
+            max_callers: 100_000,
+            caller_idle_secs: 60,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn limits(&self, namespace: &str) -> &RpcLimits {
+        self.namespaces.get(namespace).unwrap_or(&self.default_limits)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RpcLimits {
+    pub recommend: RpcLimit,
+    /// Tighter by default, every search page 1 pays for model calls.
+    pub search: RpcLimit,
+}
+
+impl Default for RpcLimits {
+    fn default() -> Self {
+        RpcLimits {
+            recommend: RpcLimit { namespace_qps: 500.0, caller_qps: 100.0, max_concurrency: 200 },
+            search: RpcLimit { namespace_qps: 100.0, caller_qps: 20.0, max_concurrency: 50 },
+        }
+    }
+}
+
+/// Zero disables the respective limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RpcLimit {
+    pub namespace_qps: f64,
+    /// Per caller within the namespace, only for callers with an authenticated `x-caller-id`.
+    pub caller_qps: f64,
+    /// In-flight requests per namespace.
+    pub max_concurrency: usize,
+}
+
+/// Suppression of sensitive hotspots, suggestions and items.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    /// Text containing a line of this file is blocked.
+    pub keywords_path: Option<String>,
+    /// Text matching a regex per line of this file is blocked.
+    pub patterns_path: Option<String>,
+    /// How often the files are checked for changes and the item blocklist is refreshed from redis.
+    pub reload_interval_secs: u64,
+    /// Ask the chat model about new hotspots that pass the lists.
+    pub llm_hotspots: bool,
+    /// Chat model verdicts kept, and for how long before the model is asked again.
+    pub llm_verdict_capacity: u64,
+    pub llm_verdict_ttl_secs: u64,
+    /// Directory of the daily audit log of suppressed content, `None` disables it.
+    pub audit_dir: Option<String>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            keywords_path: None,
+            patterns_path: None,
+            reload_interval_secs: 60,
+            llm_hotspots: false,
+            llm_verdict_capacity: 100_000,
+            llm_verdict_ttl_secs: 86400,
+            audit_dir: Some("logs".to_string()),
+        }
+    }
+}
+
+/// Dependency connects, readiness probing and the drain phase on shutdown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LifecycleConfig {
+    /// Seconds between dependency checks behind the health service.
+    pub readiness_interval_secs: u64,
+    /// Seconds in-flight rpcs, consumers and pending writes get to finish after a shutdown signal.
+    pub drain_timeout_secs: u64,
+    /// Seconds the listener keeps accepting rpcs after health turned not serving, part of `drain_timeout_secs`.
+    pub shutdown_grace_secs: u64,
+    /// Tries per dependency at startup before serving without it, 0 retries until shutdown.
+    pub connect_attempts: u32,
+    /// Backoff after the first failed try, doubled per retry up to `max_backoff_ms`.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Bounds a redis connection checkout and each redis command.
+    pub redis_timeout_ms: u64,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        LifecycleConfig {
+            readiness_interval_secs: 10,
+            drain_timeout_secs: 30,
+            shutdown_grace_secs: 5,
+            connect_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30000,
+            redis_timeout_ms: 1000,
+        }
+    }
+}
+
+/// Trace export over OTLP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP gRPC collector, e.g. `http://localhost:4317`. `None` keeps spans local to the logs.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    /// Fraction of new traces sampled, traces started upstream follow the caller's decision.
+    pub sample_ratio: f64,
+    /// Where `/metrics` is served, `None` disables it.
+    pub metrics_addr: Option<String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            otlp_endpoint: None,
+            service_name: "recommend".to_string(),
+            sample_ratio: 1.0,
+            metrics_addr: Some("127.0.0.1:9090".to_string()),
+        }
+    }
+}
+
+/// Chat model access over an OpenAI compatible endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    /// Base url the `/chat/completions` path is appended to.
+    pub base_url: String,
+    /// Environment variable holding the bearer token.
+    pub api_key_env: String,
+    /// JSON object of `input -> response` to replay instead of calling the endpoint,
+    /// `"*"` answers any other input.
+    pub replay_path: Option<String>,
+    pub default_event: PromptConfig,
+    /// Keyed by hotspot namespace, e.g. `{namespace}_search`.
+    pub events: HashMap<String, PromptConfig>,
+    pub rewrite: PromptConfig,
+    pub moderation: PromptConfig,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            base_url: "https://dashscope.aliyuncs.com/compatible-mode/v1".to_string(),
+            api_key_env: "DASHSCOPE_API_KEY".to_string(),
+            replay_path: None,
+            default_event: PromptConfig {
+                prompt: "这下面是用户的搜索词，我希望你将搜索词提炼成一个不超过十个字的热点词，判断搜索词是否有意义，并给出它所属的类目。\
+                    以JSON输出，字段为event（热点词）、is_meaningful（是否有意义）、category（类目）：\n{input}".to_string(),
+                ..PromptConfig::default()
+            },
+            events: HashMap::new(),
+            rewrite: PromptConfig {
+                prompt: "这下面是用户的搜索词，我希望你将它改写成更适合商品检索的查询，保留原意，纠正错别字，不超过二十个字。\
+                    以JSON输出，字段为query（改写后的查询）：\n{input}".to_string(),
+                ..PromptConfig::default()
+            },
+            moderation: PromptConfig {
+                prompt: "这下面是一个即将展示给用户的热点词，请判断它是否包含政治敏感、色情低俗、违法或垃圾广告内容。\
+                    以JSON输出，字段为allowed（是否可以展示）、reason（不可展示的原因，可以展示时为空）：\n{input}".to_string(),
+                temperature: Some(0.0),
+                ..PromptConfig::default()
+            },
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn event(&self, namespace: &str) -> &PromptConfig {
+        self.events.get(namespace).unwrap_or(&self.default_event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// The caller's JSON schema is sent along and enforced by the endpoint.
+    JsonSchema,
+    /// Only valid JSON is enforced, for endpoints without schema support.
+    JsonObject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptConfig {
+    pub model: String,
+    /// `{input}` is replaced by the user input.
+    pub prompt: String,
+    pub temperature: Option<f32>,
+    pub response_format: ResponseFormat,
+}
+
+impl Default for PromptConfig {
+    fn default() -> Self {
+        PromptConfig {
+            model: "qwen-turbo".to_string(),
+            prompt: "{input}".to_string(),
+            temperature: None,
+            // qwen-turbo and other DashScope models reject json_schema
+            response_format: ResponseFormat::JsonObject,
+        }
+    }
+}
+
+/// Loads the config from `RECOMMEND_CONFIG` (default `config.json`), falling back to defaults when absent.
+/// Called once at startup, a config that does not parse is an error rather than silently replaced.
+pub fn load() -> Result<&'static Config> {
+    let path = env::var("RECOMMEND_CONFIG").unwrap_or_else(|_| "config.json".to_string());
+    let config = match std::fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("[load] parse {} err.", path))?,
+        Err(e) => {
+            tracing::warn!("[load] read {} err, using defaults. err = {}", path, e);
+            Config::default()
+        }
+    };
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The config [`load`]ed at startup, defaults when nothing was loaded.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
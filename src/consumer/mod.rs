@@ -1,18 +1,20 @@
+use anyhow::{Context, Result};
 use rdkafka::{ClientConfig, Message};
 use rdkafka::config::RDKafkaLogLevel;
 use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
 use crate::handler::embedding_handler::handle_embedding_report;
 use crate::handler::hotspot_handler::handle_hotspot_report;
 use crate::dal::redis;
+use crate::{config, lifecycle, metrics, telemetry};
 use crate::recommend::ReportMessage;
 use crate::recommend::ReportType::*;
 use rocketmq::conf::LoggingFormat::Json;
 use rocketmq::conf::{ClientOption, SimpleConsumerOption};
 use rocketmq::model::common::{FilterExpression, FilterType};
 use rocketmq::SimpleConsumer;
+use tracing::Instrument;
 
-pub async fn start_rocketmq() -> ! {
-    tracing::info!("[start_rocketmq] Starting rocketmq client");
+async fn connect_rocketmq() -> Result<SimpleConsumer> {
     let mut consumer_option = SimpleConsumerOption::default();
     consumer_option.set_topics(vec!["recommend"]);
     consumer_option.set_consumer_group("test");
@@ -21,54 +23,102 @@ pub async fn start_rocketmq() -> ! {
     client_option.set_access_url("localhost:8081");
     client_option.set_enable_tls(false);
 
-    let mut consumer = SimpleConsumer::new(consumer_option, client_option).unwrap();
-    if let Err(err) = consumer.start().await {
-        panic!("[start_rocketmq] simple consumer start err. err = {:?}", err);
-    }
+    let mut consumer = SimpleConsumer::new(consumer_option, client_option)
+        .context("[connect_rocketmq] simple consumer create err.")?;
+    consumer.start().await
+        .context("[connect_rocketmq] simple consumer start err.")?;
+    Ok(consumer)
+}
 
+/// Consumes report messages until shutdown starts, finishing and acking the batch in hand.
+/// The consumer is rebuilt after `connect_attempts` receives fail in a row.
+pub async fn start_rocketmq() {
+    tracing::info!("[start_rocketmq] Starting rocketmq client");
+    let conf = &config::get().lifecycle;
+    // retried until shutdown, so an err means the consumer is no longer wanted
+    let mut consumer = match lifecycle::retry("rocketmq", 0, connect_rocketmq).await {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            tracing::error!("[start_rocketmq] connect_rocketmq err. err = {:?}", e);
+            return;
+        }
+    };
+
+    let filter = FilterExpression::new(FilterType::Tag, "*");
+    let mut failures = 0;
+    let mut backoff = lifecycle::Backoff::new();
     loop {
-        let receive_result = consumer.receive("recommend".to_string(), &FilterExpression::new(FilterType::Tag, "*")).await;
-        if receive_result.is_err() {
-            tracing::error!("[start_rocketmq] receive message err. err = {:?}",receive_result.unwrap_err());
-        } else {
-            let messages = receive_result.unwrap();
-            for message in messages {
-                match serde_json::from_slice::<ReportMessage>(message.body()) {
-                    Ok(report) => {
-                        tracing::info!("[start_rocketmq] receive message. message = {:?}", report);
-                        match report.report_type{
-                            x if x == Embedding as i32 => {
-                                if let Err(e) = handle_embedding_report(&report.namespace,report.embedding_report.unwrap_or_default()).await {
-                                    tracing::error!("[start_rocketmq] handle_embedding_report err. err = {:?}", e);
-                                    continue;
+        let receive_result = tokio::select! {
+            result = consumer.receive("recommend".to_string(), &filter) => result,
+            _ = lifecycle::shutdown_started() => break,
+        };
+        match receive_result {
+            Err(err) => {
+                tracing::error!("[start_rocketmq] receive message err. err = {:?}", err);
+                failures += 1;
+                if failures < conf.connect_attempts.max(1) {
+                    if !backoff.wait().await {
+                        break;
+                    }
+                    continue;
+                }
+                tracing::warn!("[start_rocketmq] reconnecting after {} failed receives", failures);
+                match lifecycle::retry("rocketmq", 0, connect_rocketmq).await {
+                    Ok(reconnected) => consumer = reconnected,
+                    Err(_) => break,
+                }
+                failures = 0;
+                backoff.reset();
+            }
+            Ok(messages) => {
+                failures = 0;
+                backoff.reset();
+                for message in messages {
+                    let span = telemetry::message_span("rocketmq", message.properties());
+                    let ok = match serde_json::from_slice::<ReportMessage>(message.body()) {
+                        Ok(report) => {
+                            tracing::info!("[start_rocketmq] receive message. message = {:?}", report);
+                            match report.report_type{
+                                x if x == Embedding as i32 => {
+                                    if let Err(e) = handle_embedding_report(&report.namespace,report.embedding_report.unwrap_or_default()).instrument(span.clone()).await {
+                                        tracing::error!("[start_rocketmq] handle_embedding_report err. err = {:?}", e);
+                                        metrics::observe_message("rocketmq", Some(message.born_timestamp()), false);
+                                        continue;
+                                    }
+                                    true
                                 }
-                            }
-                            //TODO:use grpc streaming
-                            x if x == HotSpot as i32 => {
-                                if let Err(e) = handle_hotspot_report(&report.namespace,report.hotspot_report.unwrap_or_default()).await {
-                                    tracing::error!("[start_rocketmq] handle_hotspot_report err. err = {:?}", e);
-                                    continue;
+                                //TODO:use grpc streaming
+                                x if x == HotSpot as i32 => {
+                                    if let Err(e) = handle_hotspot_report(&report.namespace,report.hotspot_report.unwrap_or_default()).instrument(span.clone()).await {
+                                        tracing::error!("[start_rocketmq] handle_hotspot_report err. err = {:?}", e);
+                                        metrics::observe_message("rocketmq", Some(message.born_timestamp()), false);
+                                        continue;
+                                    }
+                                    true
+                                }
+                                _ => {
+                                    tracing::error!("[start_rocketmq] unknown report type. report_type = {}", report.report_type);
+                                    false
                                 }
-                            }
-                            _ => {
-                                tracing::error!("[start_rocketmq] unknown report type. report_type = {}", report.report_type);
                             }
                         }
-                    }
-                    Err(e) => {
-                        tracing::error!("[start_rocketmq] deserializing json err. err = {}", e);
+                        Err(e) => {
+                            tracing::error!("[start_rocketmq] deserializing json err. err = {}", e);
+                            false
+                        }
+                    };
+                    metrics::observe_message("rocketmq", Some(message.born_timestamp()), ok);
+                    if let Err(e) = consumer.ack(&message).await {
+                        tracing::error!("[start_rocketmq] ack message err. err = {:?}", e);
                     }
                 }
-                if let Err(e) = consumer.ack(&message).await {
-                    tracing::error!("[start_rocketmq] ack message err. err = {:?}", e);
-                }
             }
         }
     }
+    tracing::info!("[start_rocketmq] stopped");
 }
 
-pub async fn start_kafka() -> ! {
-    tracing::info!("[start_kafka] Starting kafka client");
+async fn connect_kafka() -> Result<StreamConsumer> {
     let broker = "localhost:9092".to_string();
     let topic = "flink-topk-output";
     let group = "default".to_string();
@@ -82,12 +132,32 @@ pub async fn start_kafka() -> ! {
         //.set("auto.offset.reset", "smallest")
         .set_log_level(RDKafkaLogLevel::Info)
         .create()
-        .expect("[start_kafka] consumer create err.");
-    consumer.subscribe(&[topic]).expect("[start_kafka] subscribe to topics err.");
+        .context("[connect_kafka] consumer create err.")?;
+    consumer.subscribe(&[topic])
+        .context("[connect_kafka] subscribe to topics err.")?;
+    Ok(consumer)
+}
 
+/// Stores the Flink top-k job's output per namespace, only spawned when `hotspot.flink_topk` is on.
+pub async fn start_kafka() {
+    tracing::info!("[start_kafka] Starting kafka client");
+    let consumer = match lifecycle::retry("kafka", 0, connect_kafka).await {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            tracing::error!("[start_kafka] connect_kafka err. err = {:?}", e);
+            return;
+        }
+    };
+
+    let mut backoff = lifecycle::Backoff::new();
     loop {
-        match consumer.recv().await {
+        let received = tokio::select! {
+            received = consumer.recv() => received,
+            _ = lifecycle::shutdown_started() => break,
+        };
+        match received {
             Ok(m) => {
+                backoff.reset();
                 let payload = match m.payload_view::<str>() {
                     None => "",
                     Some(Ok(s)) => s,
@@ -107,19 +177,24 @@ pub async fn start_kafka() -> ! {
                 tracing::info!("[start_kafka] receive message. key: '{:?}', payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
                       key, payload, m.topic(), m.partition(), m.offset(), m.timestamp());
 
-                if payload != "" && key != "" {
-                    if let Err(e) = redis::set_topk(key, payload).await{
-                        tracing::error!("[start_kafka] redis set err. err = {:?}", e);
-                        continue;
-                    }
+                if !payload.is_empty() && !key.is_empty()
+                    && let Err(e) = redis::set_topk(key, payload).await {
+                    tracing::error!("[start_kafka] redis set err. err = {:?}", e);
+                    metrics::observe_message("kafka", m.timestamp().to_millis(), false);
+                    continue;
                 }
+                metrics::observe_message("kafka", m.timestamp().to_millis(), true);
                 if let Err(e)=consumer.commit_message(&m, CommitMode::Async){
                     tracing::error!("[start_kafka] commit message err. err = {:?}", e);
                 }
             }
             Err(e) => {
-                tracing::error!("[start_kafka] receive message err. err = {}", e)
+                tracing::error!("[start_kafka] receive message err. err = {}", e);
+                if !backoff.wait().await {
+                    break;
+                }
             }
         };
     }
+    tracing::info!("[start_kafka] stopped");
 }
\ No newline at end of file
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use anyhow::{bail, Result};
+use crate::error::ServiceError;
+use crate::recommend::ItemFilter;
+
+pub const VARCHAR_TYPES: &[&str] = &["VarChar"];
+const INT_TYPES: &[&str] = &["Int8", "Int16", "Int32", "Int64"];
+pub const NUMERIC_TYPES: &[&str] = &["Int8", "Int16", "Int32", "Int64", "Float", "Double"];
+
+/// Builds a Milvus boolean expression over the `item` collection, empty when nothing is filtered.
+pub fn build_item_filter(filter: Option<&ItemFilter>, exclude_ids: &[i64]) -> String {
+    let mut exprs = Vec::new();
+    if !exclude_ids.is_empty() {
+        let ids: Vec<String> = exclude_ids.iter().map(|id| id.to_string()).collect();
+        exprs.push(format!("item_id not in [{}]", ids.join(", ")));
+    }
+    if let Some(filter) = filter {
+        if !filter.categories.is_empty() {
+            let categories: Vec<String> = filter.categories.iter().map(|c| quote(c)).collect();
+            exprs.push(format!("category in [{}]", categories.join(", ")));
+        }
+        if filter.min_price > 0.0 {
+            exprs.push(format!("price >= {}", filter.min_price));
+        }
+        if filter.max_price > 0.0 {
+            exprs.push(format!("price <= {}", filter.max_price));
+        }
+        if filter.min_create_time > 0 {
+            exprs.push(format!("create_time >= {}", filter.min_create_time));
+        }
+        if filter.max_create_time > 0 {
+            exprs.push(format!("create_time <= {}", filter.max_create_time));
+        }
+        if !filter.authors.is_empty() {
+            let authors: Vec<String> = filter.authors.iter().map(|a| quote(a)).collect();
+            exprs.push(format!("author in [{}]", authors.join(", ")));
+        }
+    }
+    exprs.join(" and ")
+}
+
+/// Checks that every field the filter touches exists in the collection schema with a usable type.
+pub fn validate_item_filter(filter: &ItemFilter, fields: &HashMap<String, String>) -> Result<()> {
+    if !filter.categories.is_empty() {
+        expect_field(fields, "category", VARCHAR_TYPES)?;
+    }
+    if !filter.min_price.is_finite() || !filter.max_price.is_finite() {
+        bail!(ServiceError::Param("price range must be finite".to_string()));
+    }
+    if filter.min_price < 0.0 || filter.max_price < 0.0 {
+        bail!(ServiceError::Param("price range must not be negative".to_string()));
+    }
+    if filter.min_price > 0.0 || filter.max_price > 0.0 {
+        expect_field(fields, "price", NUMERIC_TYPES)?;
+    }
+    if filter.max_price > 0.0 && filter.min_price > filter.max_price {
+        bail!(ServiceError::Param("min_price is greater than max_price".to_string()));
+    }
+    if filter.min_create_time > 0 || filter.max_create_time > 0 {
+        expect_field(fields, "create_time", INT_TYPES)?;
+    }
+    if filter.max_create_time > 0 && filter.min_create_time > filter.max_create_time {
+        bail!(ServiceError::Param("min_create_time is greater than max_create_time".to_string()));
+    }
+    if !filter.authors.is_empty() {
+        expect_field(fields, "author", VARCHAR_TYPES)?;
+    }
+    Ok(())
+}
+
+pub fn expect_field(fields: &HashMap<String, String>, name: &str, types: &[&str]) -> Result<()> {
+    match fields.get(name) {
+        None => bail!(ServiceError::Param(format!("field {} is not in the collection schema", name))),
+        Some(data_type) if !types.contains(&data_type.as_str()) => {
+            bail!(ServiceError::Param(format!("field {} has type {}, expected one of {:?}", name, data_type, types)))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> HashMap<String, String> {
+        HashMap::from([
+            ("price".to_string(), "Double".to_string()),
+            ("category".to_string(), "VarChar".to_string()),
+        ])
+    }
+
+    fn rejected(filter: ItemFilter) -> bool {
+        validate_item_filter(&filter, &fields()).is_err_and(|e| matches!(e.downcast_ref(), Some(ServiceError::Param(_))))
+    }
+
+    #[test]
+    fn rejects_bad_price_bounds_and_unknown_fields() {
+        assert!(rejected(ItemFilter { min_price: -1.0, ..Default::default() }));
+        assert!(rejected(ItemFilter { max_price: f64::NAN, ..Default::default() }));
+        assert!(rejected(ItemFilter { min_price: f64::INFINITY, ..Default::default() }));
+        assert!(rejected(ItemFilter { min_price: 20.0, max_price: 10.0, ..Default::default() }));
+        assert!(rejected(ItemFilter { authors: vec!["someone".to_string()], ..Default::default() }));
+        assert!(rejected(ItemFilter { min_create_time: 1, ..Default::default() }));
+    }
+
+    #[test]
+    fn accepts_filters_on_schema_fields() {
+        let filter = ItemFilter {
+            categories: vec!["phone".to_string()],
+            min_price: 10.0,
+            max_price: 20.0,
+            ..Default::default()
+        };
+        assert!(validate_item_filter(&filter, &fields()).is_ok());
+        assert!(validate_item_filter(&ItemFilter::default(), &HashMap::new()).is_ok());
+    }
+}
@@ -4,23 +4,29 @@ use rdkafka::ClientConfig;
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use serde_json::json;
 use tokio::sync::OnceCell;
+use crate::metrics;
 
 static FLINK_PRODUCER:OnceCell<FutureProducer> = OnceCell::const_new();
 
-pub async fn get_flink_producer() -> &'static FutureProducer {
-    FLINK_PRODUCER.get_or_init(|| async {
+pub async fn get_flink_producer() -> Result<&'static FutureProducer> {
+    FLINK_PRODUCER.get_or_try_init(|| async {
         let broker = "localhost:9092".to_string();
         let producer: FutureProducer = ClientConfig::new()
             .set("bootstrap.servers", broker)
             .set("message.timeout.ms", "5000")
             .create()
-            .expect("Producer creation error");
-        producer
+            .context("[get_flink_producer] producer create err.")?;
+        Ok(producer)
     }).await
 }
 
+/// Feeds an event into the Flink top-k job, only called when `hotspot.flink_topk` is on.
 pub async fn send_to_flink(namespace:&str, key:&str) -> Result<()> {
-    let producer= get_flink_producer().await;
+    metrics::observe_dal("kafka", "send_to_flink", do_send_to_flink(namespace, key)).await
+}
+
+async fn do_send_to_flink(namespace:&str, key:&str) -> Result<()> {
+    let producer= get_flink_producer().await?;
     let topic = "flink-topk-input";
     let data = json!({
         "namespace": namespace,
@@ -37,4 +43,21 @@ pub async fn send_to_flink(namespace:&str, key:&str) -> Result<()> {
     .map_err(|(err, _)| Error::new(err)
         .context("[send_to_flink] send message to kafka err."))?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn send_search_log(topic:&str, key:&str, payload:&str) -> Result<()> {
+    metrics::observe_dal("kafka", "send_search_log", do_send_search_log(topic, key, payload)).await
+}
+
+async fn do_send_search_log(topic:&str, key:&str, payload:&str) -> Result<()> {
+    let producer= get_flink_producer().await?;
+    producer.send(
+        FutureRecord::to(topic)
+            .key(key)
+            .payload(payload),
+        Duration::from_secs(0),
+    ).await
+    .map_err(|(err, _)| Error::new(err)
+        .context("[send_search_log] send message to kafka err."))?;
+    Ok(())
+}
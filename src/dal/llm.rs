@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+use crate::config::{self, PromptConfig, ResponseFormat};
+
+static LLM_CLIENT: OnceCell<LlmClient> = OnceCell::const_new();
+
+pub enum LlmClient {
+    /// Any endpoint speaking the OpenAI chat completions protocol.
+    OpenAi {
+        base_url: String,
+        api_key_env: String,
+        http: reqwest::Client,
+    },
+    /// Canned responses keyed by input, for tests and offline runs.
+    Replay {
+        responses: HashMap<String, String>,
+    },
+}
+
+pub async fn get_llm_client() -> Result<&'static LlmClient> {
+    LLM_CLIENT.get_or_try_init(|| async {
+        let conf = &config::get().llm;
+        let Some(path) = &conf.replay_path else {
+            return Ok(LlmClient::OpenAi {
+                base_url: conf.base_url.trim_end_matches('/').to_string(),
+                api_key_env: conf.api_key_env.clone(),
+                http: reqwest::Client::new(),
+            });
+        };
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("[get_llm_client] read replay file {} err.", path))?;
+        let canned: HashMap<String, Value> = serde_json::from_str(&content)
+            .context("[get_llm_client] replay file parse json err.")?;
+        // responses may be written as JSON values instead of escaped strings
+        let responses = canned.into_iter()
+            .map(|(input, response)| match response {
+                Value::String(s) => (input, s),
+                other => (input, other.to_string()),
+            })
+            .collect();
+        tracing::info!("[get_llm_client] replaying canned responses from {}", path);
+        Ok(LlmClient::Replay { responses })
+    }).await
+}
+
+impl LlmClient {
+    /// Renders `conf.prompt` with `input` and decodes the JSON answer, constrained by `schema`
+    /// when the endpoint supports it.
+    pub async fn complete<T: DeserializeOwned>(&self, conf: &PromptConfig, input: &str, schema_name: &str, schema: &Value) -> Result<T> {
+        let content = match self {
+            LlmClient::OpenAi { base_url, api_key_env, http } => {
+                let api_key = env::var(api_key_env)
+                    .with_context(|| format!("[complete] env {} not set.", api_key_env))?;
+                let response_format = match conf.response_format {
+                    ResponseFormat::JsonSchema => json!({
+                        "type": "json_schema",
+                        "json_schema": {"name": schema_name, "strict": true, "schema": schema}
+                    }),
+                    ResponseFormat::JsonObject => json!({"type": "json_object"}),
+                };
+                let mut request_body = json!({
+                    "model": conf.model,
+                    "messages": [
+                        {
+                            "role": "user",
+                            "content": conf.prompt.replace("{input}", input)
+                        }
+                    ],
+                    "response_format": response_format
+                });
+                if let Some(temperature) = conf.temperature {
+                    request_body["temperature"] = json!(temperature);
+                }
+
+                let response = http
+                    .post(format!("{}/chat/completions", base_url))
+                    .header("Authorization", format!("Bearer {}", api_key))
+                    .header("Content-Type", "application/json")
+                    .json(&request_body)
+                    .send()
+                    .await
+                    .context("[complete] send request err.")?;
+
+                let result = response.json::<Value>().await
+                    .context("[complete] resp parse json err.")?;
+                result["choices"][0]["message"]["content"].as_str()
+                    .ok_or_else(|| anyhow!("[complete] no content in response. resp = {}", result))?
+                    .to_string()
+            }
+            LlmClient::Replay { responses } => responses.get(input)
+                .or_else(|| responses.get("*"))
+                .ok_or_else(|| anyhow!("[complete] no canned response for {:?}", input))?
+                .clone(),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("[complete] content parse json err. content = {}", content))
+    }
+}
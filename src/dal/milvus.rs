@@ -1,21 +1,70 @@
+use crate::config::{Rerank, SearchProfile};
 use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
 use milvus::client::Client;
-use milvus::collection::{Collection, SearchOption, SearchResult};
+use milvus::collection::{Collection, SearchOption};
 use milvus::data::FieldColumn;
 use milvus::index::MetricType::IP;
+use milvus::value::ValueVec;
 use rand::Rng;
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::OnceCell;
+use crate::metrics;
 
-static EVENT_COLLECTION_CLIENT: OnceCell<Collection> = OnceCell::const_new();
+static MILVUS_CLIENT: OnceCell<Client> = OnceCell::const_new();
+static EVENT_COLLECTIONS: OnceCell<DashMap<String, Arc<Collection>>> = OnceCell::const_new();
+static ITEM_FIELDS: OnceCell<HashMap<String, String>> = OnceCell::const_new();
 
-async fn get_event_collection() -> &'static Collection {
-    EVENT_COLLECTION_CLIENT.get_or_init(|| async {
-        let client = Client::new("http://localhost:19530").await.expect("Failed to initialize milvus client");
-        let collection = client.get_collection("event").await.expect("Failed to get event collection");
-        collection.load(1).await.expect("Failed to load event collection");
-        collection
-    }).await
+async fn get_event_collection(name: &str) -> Result<Arc<Collection>> {
+    let collections = EVENT_COLLECTIONS.get_or_init(|| async { DashMap::new() }).await;
+    if let Some(collection) = collections.get(name) {
+        return Ok(collection.clone());
+    }
+    let client = MILVUS_CLIENT.get_or_try_init(|| async {
+        Client::new("http://localhost:19530").await
+            .context("[get_event_collection] milvus client init err.")
+    }).await?;
+    let collection = client.get_collection(name).await
+        .with_context(|| format!("[get_event_collection] get collection {} err.", name))?;
+    collection.load(1).await
+        .with_context(|| format!("[get_event_collection] load collection {} err.", name))?;
+    Ok(collections.entry(name.to_string()).or_insert(Arc::new(collection)).clone())
+}
+
+/// Drops a cached collection after a failed call, so the next call fetches and loads it again.
+fn forget_event_collection(name: &str) {
+    if let Some(collections) = EVENT_COLLECTIONS.get() {
+        collections.remove(name);
+    }
+}
+
+/// Fields every event collection needs, collections created before hit tracking lack the last three.
+const EVENT_FIELDS: [&str; 5] = ["event_name", "event_embedding", "created_at", "last_seen_at", "hit_count"];
+
+/// Fails unless the event collection is reachable, loaded and has every [`EVENT_FIELDS`] field,
+/// loading it on first use.
+pub async fn check_event_collection(collection_name: &str) -> Result<()> {
+    metrics::observe_dal("milvus", "check_event_collection", do_check_event_collection(collection_name)).await
+        .inspect_err(|_| forget_event_collection(collection_name))
+}
+
+async fn do_check_event_collection(collection_name: &str) -> Result<()> {
+    let collection = get_event_collection(collection_name).await?;
+    let loaded = collection.is_loaded().await
+        .context("[check_event_collection] collection is_loaded err.")?;
+    if !loaded {
+        return Err(anyhow!("[check_event_collection] {} is not loaded", collection_name));
+    }
+    let missing: Vec<&str> = EVENT_FIELDS.into_iter()
+        .filter(|name| collection.schema().get_field(*name).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!("[check_event_collection] {} lacks fields {:?}, recreate it with the int64 created_at, last_seen_at and hit_count fields",
+            collection_name, missing));
+    }
+    Ok(())
 }
 
 pub fn random_embedding() -> Vec<f32> {
@@ -23,25 +72,253 @@ pub fn random_embedding() -> Vec<f32> {
     (0..1024).map(|_| rng.random_range(-0.05..0.05)).collect()
 }
 
-pub async fn insert_event(event_name: &str, embedding_data: Vec<f32>) -> Result<()> {
-    let collection = get_event_collection().await;
-    let event_name_column = FieldColumn::new(collection.schema().get_field("event_name").unwrap(), vec![event_name.to_string()], );
-    let event_embedding_column = FieldColumn::new(collection.schema().get_field("event_embedding").unwrap(), embedding_data);
-    collection.insert(vec![event_embedding_column, event_name_column], None).await
-        .context("[insert_event] collection insert err.")?;
+/// One row of an event collection, timestamps in unix seconds.
+pub struct EventRow {
+    pub event_name: String,
+    pub embedding: Vec<f32>,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    pub hit_count: i64,
+}
+
+/// Inserts a batch of new events and flushes once for the whole batch.
+pub async fn insert_events(collection_name: &str, rows: &[EventRow]) -> Result<()> {
+    metrics::observe_dal("milvus", "insert_events", do_insert_events(collection_name, rows)).await
+        .inspect_err(|_| forget_event_collection(collection_name))
+}
+
+async fn do_insert_events(collection_name: &str, rows: &[EventRow]) -> Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let collection = get_event_collection(collection_name).await?;
+    let schema = collection.schema();
+    let field = |name: &str| schema.get_field(name)
+        .ok_or_else(|| anyhow!("[insert_events] no {} field in {}", name, collection_name));
+    let mut names = Vec::with_capacity(rows.len());
+    let mut embeddings = Vec::with_capacity(rows.len() * rows.first().map_or(0, |r| r.embedding.len()));
+    let mut created_at = Vec::with_capacity(rows.len());
+    let mut last_seen_at = Vec::with_capacity(rows.len());
+    let mut hit_count = Vec::with_capacity(rows.len());
+    for row in rows {
+        names.push(row.event_name.clone());
+        embeddings.extend_from_slice(&row.embedding);
+        created_at.push(row.created_at);
+        last_seen_at.push(row.last_seen_at);
+        hit_count.push(row.hit_count);
+    }
+    let columns = vec![
+        FieldColumn::new(field("event_embedding")?, embeddings),
+        FieldColumn::new(field("event_name")?, names),
+        FieldColumn::new(field("created_at")?, created_at),
+        FieldColumn::new(field("last_seen_at")?, last_seen_at),
+        FieldColumn::new(field("hit_count")?, hit_count),
+    ];
+    collection.insert(columns, None).await
+        .context("[insert_events] collection insert err.")?;
     collection.flush().await
-        .context("[insert_event] collection flush err.")?;
+        .context("[insert_events] collection flush err.")?;
     Ok(())
 }
-pub async fn recall_event<'a>(embedding: Vec<f32>) -> Result<Option<SearchResult<'a>>> {
-    let collection = get_event_collection().await;
+/// Adds `(hits, last_seen_at)` to stored events. Milvus only rewrites whole rows, so the rows are read
+/// back and written with just `hit_count` and `last_seen_at` changed. An autoID primary key cannot be
+/// upserted, such rows are inserted under a new key and the old ones deleted by key.
+pub async fn touch_events(collection_name: &str, hits: &HashMap<String, (i64, i64)>) -> Result<()> {
+    metrics::observe_dal("milvus", "touch_events", do_touch_events(collection_name, hits)).await
+        .inspect_err(|_| forget_event_collection(collection_name))
+}
+
+async fn do_touch_events(collection_name: &str, hits: &HashMap<String, (i64, i64)>) -> Result<()> {
+    if hits.is_empty() {
+        return Ok(());
+    }
+    let collection = get_event_collection(collection_name).await?;
+    let schema = collection.schema();
+    let pk = schema.primary_column()
+        .ok_or_else(|| anyhow!("[touch_events] no primary key in {}", collection_name))?;
+    let (pk_name, auto_id) = (pk.name.clone(), pk.auto_id);
+    let mut output_fields = vec![pk_name.as_str()];
+    output_fields.extend(EVENT_FIELDS);
+    let names: Vec<Value> = hits.keys().map(|name| json!(name)).collect();
+    let body = json!({
+        "collectionName": collection_name,
+        "filter": format!("event_name in {}", Value::Array(names)),
+        "limit": hits.len(),
+        "outputFields": output_fields
+    });
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://localhost:19530/v2/vectordb/entities/query")
+        .json(&body)
+        .send()
+        .await
+        .context("[touch_events] query send request err.")?;
+    let result = response.json::<Value>().await
+        .context("[touch_events] query resp parse json err.")?;
+    let rows = result.get("data")
+        .and_then(|d| d.as_array())
+        .cloned()
+        .ok_or_else(|| anyhow!("no data field"))?;
+    let (rows, old_keys) = touched_rows(rows, hits, &pk_name, auto_id);
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let body = json!({
+        "data": rows,
+        "collectionName": collection_name
+    });
+    let path = if auto_id { "insert" } else { "upsert" };
+    let response = client
+        .post(format!("http://localhost:19530/v2/vectordb/entities/{}", path))
+        .json(&body)
+        .send()
+        .await
+        .context("[touch_events] write send request err.")?;
+    let result = response.json::<Value>().await
+        .context("[touch_events] write resp parse json err.")?;
+    tracing::debug!("[touch_events] {}", result);
+    if result.get("code").and_then(Value::as_i64).unwrap_or(0) != 0 {
+        return Err(anyhow!("[touch_events] {} rejected. resp = {}", path, result));
+    }
+    if old_keys.is_empty() {
+        return Ok(());
+    }
+
+    // the new rows are written first, a failed delete leaves a duplicate rather than losing the event
+    let body = json!({
+        "collectionName": collection_name,
+        "filter": format!("{} in {}", pk_name, Value::Array(old_keys))
+    });
+    let response = client
+        .post("http://localhost:19530/v2/vectordb/entities/delete")
+        .json(&body)
+        .send()
+        .await
+        .context("[touch_events] delete send request err.")?;
+    let result = response.json::<Value>().await
+        .context("[touch_events] delete resp parse json err.")?;
+    tracing::debug!("[touch_events] {}", result);
+    Ok(())
+}
+
+/// The queried rows with their hits added, and the primary keys to delete once they are written.
+/// With an autoID key the rows drop their key so milvus assigns a new one.
+fn touched_rows(rows: Vec<Value>, hits: &HashMap<String, (i64, i64)>, pk_name: &str, auto_id: bool) -> (Vec<Value>, Vec<Value>) {
+    let mut touched = Vec::with_capacity(rows.len());
+    let mut old_keys = Vec::new();
+    for mut row in rows {
+        let Some(obj) = row.as_object_mut() else { continue };
+        let Some(&(count, last_seen)) = obj.get("event_name").and_then(|n| n.as_str()).and_then(|n| hits.get(n)) else {
+            continue;
+        };
+        let hit_count = obj.get("hit_count").and_then(|c| c.as_i64()).unwrap_or(0) + count;
+        let last_seen_at = obj.get("last_seen_at").and_then(|t| t.as_i64()).unwrap_or(0).max(last_seen);
+        obj.insert("hit_count".to_string(), json!(hit_count));
+        obj.insert("last_seen_at".to_string(), json!(last_seen_at));
+        if auto_id {
+            let Some(key) = obj.remove(pk_name) else { continue };
+            old_keys.push(key);
+        }
+        touched.push(row);
+    }
+    (touched, old_keys)
+}
+
+/// Nearest events by inner product as `(event_name, score)`, best first.
+pub async fn recall_event(collection_name: &str, embedding: Vec<f32>, top_k: i32) -> Result<Vec<(String, f32)>> {
+    metrics::observe_dal("milvus", "recall_event", do_recall_event(collection_name, embedding, top_k)).await
+        .inspect_err(|_| forget_event_collection(collection_name))
+}
+
+async fn do_recall_event(collection_name: &str, embedding: Vec<f32>, top_k: i32) -> Result<Vec<(String, f32)>> {
+    let collection = get_event_collection(collection_name).await?;
     let options = SearchOption::default();
-    let results = collection.search(vec![embedding.into()], "event_embedding", 1, IP, vec!["event_name"], &options, ).await
+    let results = collection.search(vec![embedding.into()], "event_embedding", top_k, IP, vec!["event_name"], &options, ).await
         .context("[recall_event] collection search err.")?;
-    Ok(results.into_iter().next())
+    let Some(result) = results.into_iter().next() else {
+        return Ok(Vec::new());
+    };
+    let Some(ValueVec::String(event_names)) = result.field.into_iter().next().map(|c| c.value) else {
+        return Ok(Vec::new());
+    };
+    Ok(event_names.into_iter().zip(result.score).collect())
+}
+/// Up to `limit` events matching `filter`, for offline maintenance of the collection.
+pub async fn query_events(collection_name: &str, filter: &str, limit: usize) -> Result<Vec<EventRow>> {
+    metrics::observe_dal("milvus", "query_events", do_query_events(collection_name, filter, limit)).await
+}
+
+async fn do_query_events(collection_name: &str, filter: &str, limit: usize) -> Result<Vec<EventRow>> {
+    let body = json!({
+        "collectionName": collection_name,
+        "filter": filter,
+        "limit": limit,
+        "outputFields": ["event_name", "event_embedding", "created_at", "last_seen_at", "hit_count"]
+    });
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://localhost:19530/v2/vectordb/entities/query")
+        .json(&body)
+        .send()
+        .await
+        .context("[query_events] send request err.")?;
+
+    let result = response.json::<Value>().await
+        .context("[query_events] resp parse json err.")?;
+    let data = result.get("data")
+        .and_then(|d| d.as_array())
+        .ok_or_else(|| anyhow!("no data field"))?;
+    Ok(data.iter()
+        .filter_map(|obj| {
+            let int = |field: &str| obj.get(field).and_then(|v| v.as_i64()).unwrap_or(0);
+            Some(EventRow {
+                event_name: obj.get("event_name")?.as_str()?.to_string(),
+                embedding: obj.get("event_embedding")?.as_array()?.iter()
+                    .filter_map(|x| x.as_f64().map(|f| f as f32))
+                    .collect(),
+                created_at: int("created_at"),
+                last_seen_at: int("last_seen_at"),
+                hit_count: int("hit_count"),
+            })
+        })
+        .collect())
+}
+pub async fn delete_events(collection_name: &str, event_names: &[String]) -> Result<()> {
+    metrics::observe_dal("milvus", "delete_events", do_delete_events(collection_name, event_names)).await
+}
+
+async fn do_delete_events(collection_name: &str, event_names: &[String]) -> Result<()> {
+    if event_names.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<Value> = event_names.iter().map(|name| json!(name)).collect();
+    let body = json!({
+        "collectionName": collection_name,
+        "filter": format!("event_name in {}", Value::Array(names))
+    });
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://localhost:19530/v2/vectordb/entities/delete")
+        .json(&body)
+        .send()
+        .await
+        .context("[delete_events] send request err.")?;
+
+    let result = response.json::<Value>().await
+        .context("[delete_events] resp parse json err.")?;
+    tracing::debug!("[delete_events] {}", result);
+    Ok(())
 }
 
 pub async fn upsert_item(extra: &str, embedding_data: Vec<f32>) -> Result<()> {
+    metrics::observe_dal("milvus", "upsert_item", do_upsert_item(extra, embedding_data)).await
+}
+
+async fn do_upsert_item(extra: &str, embedding_data: Vec<f32>) -> Result<()> {
     let mut extra_obj: Value = serde_json::from_str(extra)
         .context("[upsert_item] extra deserializing json err.")?;
     let obj = extra_obj.as_object_mut()
@@ -63,10 +340,14 @@ pub async fn upsert_item(extra: &str, embedding_data: Vec<f32>) -> Result<()> {
 
     let result = response.json::<Value>().await
         .context("[upsert_item] resp parse json err.")?;
-    tracing::info!("[upsert_item] {}", result);
+    tracing::debug!("[upsert_item] {}", result);
     Ok(())
 }
 pub async fn get_item_embedding(ids: Vec<i64>) -> Result<Vec<Vec<f32>>> {
+    metrics::observe_dal("milvus", "get_item_embedding", do_get_item_embedding(ids)).await
+}
+
+async fn do_get_item_embedding(ids: Vec<i64>) -> Result<Vec<Vec<f32>>> {
     let body = json!({
         "collectionName": "item",
         "id": ids,
@@ -99,26 +380,45 @@ pub async fn get_item_embedding(ids: Vec<i64>) -> Result<Vec<Vec<f32>>> {
     }
     Ok(embeddings)
 }
-pub async fn recall_item(embeddings: Vec<Vec<f32>>,step:i64) -> Result<Vec<Value>> {
-    let limits = [15, 12, 9, 6, 3, 5];
+/// Candidates each embedding leg of [`recall_item`] adds per step, the first leg leads.
+const RECALL_LIMITS: [i64; 6] = [15, 12, 9, 6, 3, 5];
+/// Milvus rejects a search whose offset plus limit exceeds this.
+const MAX_SEARCH_WINDOW: i64 = 16384;
+
+/// The deepest step every leg of [`recall_item`] can page to.
+pub fn max_recall_step() -> i64 {
+    MAX_SEARCH_WINDOW / RECALL_LIMITS.iter().max().copied().unwrap_or(1)
+}
+
+pub async fn recall_item(embeddings: Vec<Vec<f32>>, step: i64, filter: &str) -> Result<Vec<Value>> {
+    metrics::observe_dal("milvus", "recall_item", do_recall_item(embeddings, step, filter)).await
+}
+
+fn recall_item_body(embeddings: Vec<Vec<f32>>, step: i64, filter: &str) -> Value {
     let req: Vec<Value> = embeddings
         .into_iter()
-        .zip(limits.iter())
+        .zip(RECALL_LIMITS.iter())
         .map(|(embedding, &limit)| {
-            json!({
+            let offset = (step - 1) * limit;
+            let mut search = json!({
                 "data": [embedding],
                 "annsField": "multi_embedding",
                 "params": {
                     "params": {
-                        "ef": 10
+                        // milvus rejects an ef below the offset plus limit
+                        "ef": (offset + limit).max(10)
                     }
                 },
-                "offset": (step - 1) * limit,
+                "offset": offset,
                 "limit": limit
-            })
+            });
+            if !filter.is_empty() {
+                search["filter"] = json!(filter);
+            }
+            search
         })
         .collect();
-    let body = json!({
+    json!({
         "collectionName": "item",
         "search": req,
         "rerank": {
@@ -133,7 +433,11 @@ pub async fn recall_item(embeddings: Vec<Vec<f32>>,step:i64) -> Result<Vec<Value
             "title",
             "image"
         ]
-    });
+    })
+}
+
+async fn do_recall_item(embeddings: Vec<Vec<f32>>, step: i64, filter: &str) -> Result<Vec<Value>> {
+    let body = recall_item_body(embeddings, step, filter);
 
     let client = reqwest::Client::new();
     let response = client
@@ -143,83 +447,290 @@ pub async fn recall_item(embeddings: Vec<Vec<f32>>,step:i64) -> Result<Vec<Value
         .await
         .context("[recall_item] send request err.")?;
 
-    let mut result = response.json::<Value>().await
+    let result = response.json::<Value>().await
         .context("[recall_item] resp parse json err.")?;
-    tracing::info!("[recall_item] {}", result);
+    tracing::debug!("[recall_item] {}", result);
+    shape_items(result)
+}
+/// Fetches the fused top `window_size` of both legs, pages are sliced from it by the caller.
+pub async fn search_item(embedding: Vec<f32>, keyword: &str, filter: &str, extra_fields: &[&str], profile: &SearchProfile) -> Result<Vec<Value>> {
+    metrics::observe_dal("milvus", "search_item", do_search_item(embedding, keyword, filter, extra_fields, profile)).await
+}
 
-    let data = result.get_mut("data")
-        .and_then(|d| d.as_array_mut())
-        .ok_or_else(|| anyhow!("no data field"))?;
-    for obj in data.iter_mut() {
-        if let Some(map) = obj.as_object_mut() {
-            map.remove("distance");
-            map.remove("id");
-        }
-    }
-    Ok(data.to_owned())
+async fn do_search_item(embedding: Vec<f32>, keyword: &str, filter: &str, extra_fields: &[&str], profile: &SearchProfile) -> Result<Vec<Value>> {
+    let body = search_item_body(embedding, keyword, filter, extra_fields, profile);
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://localhost:19530/v2/vectordb/entities/advanced_search")
+        .json(&body)
+        .send()
+        .await
+        .context("[search_item] send request err.")?;
+
+    let result = response.json::<Value>().await
+        .context("[search_item] resp parse json err.")?;
+    tracing::debug!("[search_item] {}", result);
+    shape_items(result)
 }
-pub async fn search_item(embedding: Vec<f32>, keyword: &str, page: i64) -> Result<Vec<Value>> {
-    let req = json!([
+
+fn search_item_body(embedding: Vec<f32>, keyword: &str, filter: &str, extra_fields: &[&str], profile: &SearchProfile) -> Value {
+    let mut req = json!([
         {
             "data": [embedding],
             "annsField": "multi_embedding",
             "params": {
                 "params": {
-                    "ef": 10
+                    "ef": profile.ef
                 }
             },
-            "offset": (page - 1) * 10,
-            "limit": 10
+            "limit": profile.dense_limit
         },
         {
             "data": [keyword],
             "annsField": "title_embeddings",
             "params": {
                 "params": {
-                    "drop_ratio_build": 0.2
+                    "drop_ratio_search": profile.drop_ratio_search
                 }
             },
-            "offset": (page - 1) * 10,
-            "limit": 10
+            "limit": profile.sparse_limit
         }
     ]);
-    let body = json!({
+    if !filter.is_empty() {
+        for search in req.as_array_mut().into_iter().flatten() {
+            search["filter"] = json!(filter);
+        }
+    }
+    let mut output_fields = vec!["item_id", "title", "image"];
+    output_fields.extend_from_slice(extra_fields);
+    let rerank = match &profile.rerank {
+        Rerank::Rrf { k } => json!({
+            "strategy": "rrf",
+            "params": {
+                "k": k
+            }
+        }),
+        Rerank::Weighted { weights } => json!({
+            "strategy": "weighted",
+            "params": {
+                "weights": weights
+            }
+        }),
+    };
+    json!({
         "collectionName": "item",
         "search": req,
-        "rerank": {
-            "strategy": "rrf",
+        "rerank": rerank,
+        "limit": profile.window_size,
+        "outputFields": output_fields
+    })
+}
+/// Field name to Milvus data type of the `item` collection, described once and kept for the process lifetime.
+pub async fn get_item_fields() -> Result<&'static HashMap<String, String>> {
+    metrics::observe_dal("milvus", "get_item_fields", do_get_item_fields()).await
+}
+
+async fn do_get_item_fields() -> Result<&'static HashMap<String, String>> {
+    ITEM_FIELDS.get_or_try_init(|| async {
+        let body = json!({
+            "collectionName": "item"
+        });
+        let client = reqwest::Client::new();
+        let response = client
+            .post("http://localhost:19530/v2/vectordb/collections/describe")
+            .json(&body)
+            .send()
+            .await
+            .context("[get_item_fields] send request err.")?;
+
+        let result = response.json::<Value>().await
+            .context("[get_item_fields] resp parse json err.")?;
+        let fields = result.pointer("/data/fields")
+            .and_then(|f| f.as_array())
+            .ok_or_else(|| anyhow!("no fields in collection description"))?;
+        let fields = fields.iter()
+            .filter_map(|field| {
+                let name = field.get("name")?.as_str()?;
+                let data_type = field.get("type")?.as_str()?;
+                Some((name.to_string(), data_type.to_string()))
+            })
+            .collect();
+        Ok(fields)
+    }).await
+}
+/// Searches with an HNSW breadth of `ef`, raised to `limit` since milvus rejects a smaller one.
+pub async fn search_similar_item(embedding: Vec<f32>, limit: usize, ef: usize, filter: &str) -> Result<Vec<Value>> {
+    metrics::observe_dal("milvus", "search_similar_item", do_search_similar_item(embedding, limit, ef, filter)).await
+}
+
+fn similar_item_body(embedding: Vec<f32>, limit: usize, ef: usize, filter: &str) -> Value {
+    let mut body = json!({
+        "collectionName": "item",
+        "data": [embedding],
+        "annsField": "multi_embedding",
+        "searchParams": {
             "params": {
-                "k": 60
+                "ef": ef.max(limit)
             }
         },
-        "limit": 20,
+        "limit": limit,
         "outputFields": [
             "item_id",
             "title",
             "image"
         ]
     });
+    if !filter.is_empty() {
+        body["filter"] = json!(filter);
+    }
+    body
+}
+
+async fn do_search_similar_item(embedding: Vec<f32>, limit: usize, ef: usize, filter: &str) -> Result<Vec<Value>> {
+    let body = similar_item_body(embedding, limit, ef, filter);
 
     let client = reqwest::Client::new();
     let response = client
-        .post("http://localhost:19530/v2/vectordb/entities/advanced_search")
+        .post("http://localhost:19530/v2/vectordb/entities/search")
         .json(&body)
         .send()
         .await
-        .context("[search_item] send request err.")?;
+        .context("[search_similar_item] send request err.")?;
 
-    let mut result = response.json::<Value>().await
-        .context("[search_item] resp parse json err.")?;
-    tracing::info!("[search_item] {}", result);
+    let result = response.json::<Value>().await
+        .context("[search_similar_item] resp parse json err.")?;
+    tracing::debug!("[search_similar_item] {}", result);
+    shape_items(result)
+}
+
+pub async fn query_item_titles(prefix: &str, limit: usize) -> Result<Vec<String>> {
+    metrics::observe_dal("milvus", "query_item_titles", do_query_item_titles(prefix, limit)).await
+}
+
+async fn do_query_item_titles(prefix: &str, limit: usize) -> Result<Vec<String>> {
+    let escaped: String = prefix.chars()
+        .flat_map(|c| match c {
+            '"' | '\\' | '%' | '_' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect();
+    let body = json!({
+        "collectionName": "item",
+        "filter": format!("title like \"{}%\"", escaped),
+        "limit": limit,
+        "outputFields": ["title"]
+    });
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://localhost:19530/v2/vectordb/entities/query")
+        .json(&body)
+        .send()
+        .await
+        .context("[query_item_titles] send request err.")?;
+
+    let result = response.json::<Value>().await
+        .context("[query_item_titles] resp parse json err.")?;
+    let data = result.get("data")
+        .and_then(|d| d.as_array())
+        .ok_or_else(|| anyhow!("no data field"))?;
+    Ok(data.iter()
+        .filter_map(|obj| obj.get("title").and_then(Value::as_str).map(str::to_string))
+        .collect())
+}
 
+/// Strips search metadata from a REST search response and drops repeated items.
+fn shape_items(mut result: Value) -> Result<Vec<Value>> {
     let data = result.get_mut("data")
         .and_then(|d| d.as_array_mut())
         .ok_or_else(|| anyhow!("no data field"))?;
-    for obj in data.iter_mut() {
+    let mut items: Vec<Value> = Vec::with_capacity(data.len());
+    for mut obj in data.drain(..) {
         if let Some(map) = obj.as_object_mut() {
             map.remove("distance");
             map.remove("id");
         }
+        if !items.contains(&obj) {
+            items.push(obj);
+        }
     }
-    Ok(data.to_owned())
-}
\ No newline at end of file
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SimilarConfig;
+
+    #[test]
+    fn default_search_request_has_ef_at_least_limit() {
+        let profile = SearchProfile::default();
+        let body = search_item_body(vec![0.0; 4], "phone", "", &[], &profile);
+        let dense = &body["search"][0];
+        assert_eq!(dense["annsField"], "multi_embedding");
+        let ef = dense["params"]["params"]["ef"].as_u64().unwrap();
+        let limit = dense["limit"].as_u64().unwrap();
+        assert!(ef >= limit, "ef {} below limit {}", ef, limit);
+        assert!(body["search"][0].get("filter").is_none());
+    }
+
+    fn event_row(id: i64, name: &str) -> Value {
+        json!({"id": id, "event_name": name, "event_embedding": [0.1, 0.2], "created_at": 10, "last_seen_at": 20, "hit_count": 3})
+    }
+
+    #[test]
+    fn touched_auto_id_rows_get_a_new_key() {
+        let hits = HashMap::from([("a".to_string(), (2, 50))]);
+        let (rows, old_keys) = touched_rows(vec![event_row(7, "a"), event_row(8, "b")], &hits, "id", true);
+        assert_eq!(rows, vec![json!({"event_name": "a", "event_embedding": [0.1, 0.2], "created_at": 10, "last_seen_at": 50, "hit_count": 5})]);
+        assert_eq!(old_keys, vec![json!(7)]);
+    }
+
+    #[test]
+    fn touched_rows_keep_an_explicit_key() {
+        let hits = HashMap::from([("a".to_string(), (1, 5))]);
+        let (rows, old_keys) = touched_rows(vec![event_row(7, "a")], &hits, "id", false);
+        assert_eq!(rows[0]["id"], 7);
+        // an older hit never moves last_seen_at back
+        assert_eq!(rows[0]["last_seen_at"], 20);
+        assert_eq!(rows[0]["hit_count"], 4);
+        assert!(old_keys.is_empty());
+    }
+
+    #[test]
+    fn similar_request_has_ef_at_least_limit() {
+        let conf = SimilarConfig::default();
+        // cached searches fetch max_limit, uncached ones the requested or default limit
+        for limit in [conf.max_limit, conf.default_limit, 1] {
+            let body = similar_item_body(vec![0.0; 4], limit, conf.ef, "");
+            let ef = body["searchParams"]["params"]["ef"].as_u64().unwrap() as usize;
+            assert!(ef >= limit, "ef {} below limit {}", ef, limit);
+            assert_eq!(body["limit"].as_u64().unwrap() as usize, limit);
+        }
+        let body = similar_item_body(vec![0.0; 4], 5, 64, "");
+        assert_eq!(body["searchParams"]["params"]["ef"], 64);
+    }
+
+    #[test]
+    fn recall_stays_within_the_search_window() {
+        let step = max_recall_step();
+        let body = recall_item_body(vec![vec![0.0; 4]; 6], step, "");
+        for search in body["search"].as_array().unwrap() {
+            let offset = search["offset"].as_i64().unwrap();
+            let limit = search["limit"].as_i64().unwrap();
+            let ef = search["params"]["params"]["ef"].as_i64().unwrap();
+            assert!(offset + limit <= MAX_SEARCH_WINDOW, "offset {} limit {}", offset, limit);
+            assert!(ef >= offset + limit, "ef {} below offset {} limit {}", ef, offset, limit);
+        }
+        let first = recall_item_body(vec![vec![0.0; 4]], step + 1, "");
+        assert!(first["search"][0]["offset"].as_i64().unwrap() + 15 > MAX_SEARCH_WINDOW);
+    }
+
+    #[test]
+    fn search_request_applies_filter_to_both_legs() {
+        let body = search_item_body(vec![0.0; 4], "phone", "price < 10", &["category"], &SearchProfile::default());
+        assert_eq!(body["search"][0]["filter"], "price < 10");
+        assert_eq!(body["search"][1]["filter"], "price < 10");
+        assert_eq!(body["outputFields"], json!(["item_id", "title", "image", "category"]));
+    }
+}
@@ -1,4 +1,8 @@
+pub mod filter;
+pub mod llm;
 pub mod milvus;
 pub mod model;
+pub mod moderation_log;
 pub mod redis;
-pub mod kafka;
\ No newline at end of file
+pub mod kafka;
+pub mod search_log;
\ No newline at end of file
@@ -1,8 +1,16 @@
+use crate::config::{self, PromptConfig};
+use crate::dal::llm::{self, LlmClient};
 use anyhow::{Context, Result};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::env;
+use crate::metrics;
 
 pub async fn call_multi_embedding_model(texts: &Vec<String>, images: &Vec<String>, videos:&Vec<String>) -> Result<Vec<f32>> {
+    metrics::observe_dal("dashscope", "call_multi_embedding_model", do_call_multi_embedding_model(texts, images, videos)).await
+}
+
+async fn do_call_multi_embedding_model(texts: &Vec<String>, images: &Vec<String>, videos:&Vec<String>) -> Result<Vec<f32>> {
     let api_key = env::var("DASHSCOPE_API_KEY")?;
     let client = reqwest::Client::new();
 
@@ -42,6 +50,10 @@ pub async fn call_multi_embedding_model(texts: &Vec<String>, images: &Vec<String
 }
 
 pub async fn call_text_embedding_model(event: &str) -> Result<Vec<f32>> {
+    metrics::observe_dal("dashscope", "call_text_embedding_model", do_call_text_embedding_model(event)).await
+}
+
+async fn do_call_text_embedding_model(event: &str) -> Result<Vec<f32>> {
     let api_key = env::var("DASHSCOPE_API_KEY")?;
     let client = reqwest::Client::new();
 
@@ -72,37 +84,119 @@ pub async fn call_text_embedding_model(event: &str) -> Result<Vec<f32>> {
     Ok(embedding)
 }
 
-pub async fn call_event_model(keyword: &str) -> Result<String> {
-    let api_key = env::var("DASHSCOPE_API_KEY")?;
-    let client = reqwest::Client::new();
-    let prompt = "这下面是用户的搜索词，我希望你将搜索词提炼成一个热点词，要求不超过十个字，如果搜索词无意义请输出null，我希望你只输出热点词内容：\n";
-    let request_body = json!({
-        //"model": "qwen2.5-1.5b-instruct",
-        "model": "qwen-turbo",
-        "messages": [
-            {
-                "role": "user",
-                "content": format!("{}{}",prompt,keyword)
-            }
-        ]
+/// What the chat model distilled from one search keyword.
+#[derive(Debug, Deserialize)]
+pub struct ExtractedEvent {
+    pub event: String,
+    pub is_meaningful: bool,
+    pub category: String,
+}
+
+pub async fn call_event_model(namespace: &str, keyword: &str) -> Result<ExtractedEvent> {
+    metrics::observe_dal("dashscope", "call_event_model", do_call_event_model(namespace, keyword)).await
+}
+
+async fn do_call_event_model(namespace: &str, keyword: &str) -> Result<ExtractedEvent> {
+    let client = llm::get_llm_client().await
+        .context("[call_event_model] get_llm_client err.")?;
+    extract_event(client, config::get().llm.event(namespace), keyword).await
+        .context("[call_event_model] extract_event err.")
+}
+
+async fn extract_event(client: &LlmClient, conf: &PromptConfig, keyword: &str) -> Result<ExtractedEvent> {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "event": {"type": "string"},
+            "is_meaningful": {"type": "boolean"},
+            "category": {"type": "string"}
+        },
+        "required": ["event", "is_meaningful", "category"],
+        "additionalProperties": false
     });
+    client.complete(conf, keyword, "hotspot_event", &schema).await
+        .context("[extract_event] complete err.")
+}
 
-    let response = client
-        .post("https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await
-        .context("[call_event_model] send request err.")?;
+pub async fn call_rewrite_model(query: &str) -> Result<String> {
+    metrics::observe_dal("dashscope", "call_rewrite_model", do_call_rewrite_model(query)).await
+}
 
-    let result = response.json::<Value>().await
-        .context("[call_event_model] resp parse json err.")?;
-    let content = result["choices"][0]["message"]["content"].as_str().unwrap_or("null");
-    let event = if content.len() >= 2 && content.starts_with('"') && content.ends_with('"') {
-        &content[1..content.len() - 1]
-    } else {
-        content
-    };
-    Ok(event.to_string())
+async fn do_call_rewrite_model(query: &str) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Rewrite {
+        query: String,
+    }
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "query": {"type": "string"}
+        },
+        "required": ["query"],
+        "additionalProperties": false
+    });
+    let client = llm::get_llm_client().await
+        .context("[call_rewrite_model] get_llm_client err.")?;
+    let rewrite: Rewrite = client.complete(&config::get().llm.rewrite, query, "query_rewrite", &schema).await
+        .context("[call_rewrite_model] complete err.")?;
+    Ok(rewrite.query)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationVerdict {
+    pub allowed: bool,
+    pub reason: String,
+}
+
+pub async fn call_moderation_model(text: &str) -> Result<ModerationVerdict> {
+    metrics::observe_dal("dashscope", "call_moderation_model", do_call_moderation_model(text)).await
+}
+
+async fn do_call_moderation_model(text: &str) -> Result<ModerationVerdict> {
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "allowed": {"type": "boolean"},
+            "reason": {"type": "string"}
+        },
+        "required": ["allowed", "reason"],
+        "additionalProperties": false
+    });
+    let client = llm::get_llm_client().await
+        .context("[call_moderation_model] get_llm_client err.")?;
+    client.complete(&config::get().llm.moderation, text, "moderation_verdict", &schema).await
+        .context("[call_moderation_model] complete err.")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::config::ResponseFormat;
+
+    #[test]
+    fn prompts_default_to_json_object() {
+        let conf = config::LlmConfig::default();
+        assert_eq!(conf.default_event.response_format, ResponseFormat::JsonObject);
+        assert_eq!(conf.rewrite.response_format, ResponseFormat::JsonObject);
+        assert_eq!(conf.moderation.response_format, ResponseFormat::JsonObject);
+    }
+
+    #[tokio::test]
+    async fn event_model_decodes_replayed_answers() {
+        let client = LlmClient::Replay {
+            responses: HashMap::from([
+                ("新款手机发布会".to_string(), r#"{"event":"手机发布会","is_meaningful":true,"category":"数码"}"#.to_string()),
+                ("asdf".to_string(), r#"{"event":""}"#.to_string()),
+            ]),
+        };
+        let conf = config::LlmConfig::default().default_event;
+        let extracted = extract_event(&client, &conf, "新款手机发布会").await.unwrap();
+        assert_eq!(extracted.event, "手机发布会");
+        assert!(extracted.is_meaningful);
+        assert_eq!(extracted.category, "数码");
+        // missing fields and inputs without a canned answer are errors, not defaults
+        assert!(extract_event(&client, &conf, "asdf").await.is_err());
+        assert!(extract_event(&client, &conf, "unknown").await.is_err());
+    }
 }
@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use time::{Date, OffsetDateTime};
+use tokio::fs::File;
+use tokio::sync::Mutex;
+use crate::config;
+use crate::dal::search_log;
+
+static LOG_FILE: Mutex<Option<(Date, File)>> = Mutex::const_new(None);
+
+/// One suppressed hotspot, suggestion or item.
+#[derive(Serialize)]
+pub struct ModerationLog {
+    /// Where it was suppressed, e.g. `hotspot`, `suggest`, `recommend`.
+    pub surface: String,
+    pub namespace: String,
+    pub subject: String,
+    pub reason: String,
+    pub timestamp: i64,
+}
+
+impl ModerationLog {
+    pub fn new(surface: &str, namespace: &str, subject: &str, reason: &str) -> Self {
+        ModerationLog {
+            surface: surface.to_string(),
+            namespace: namespace.to_string(),
+            subject: subject.to_string(),
+            reason: reason.to_string(),
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+        }
+    }
+}
+
+/// Appends one JSON line to the daily audit file, a no-op without `audit_dir`.
+pub async fn write_moderation_log(log: &ModerationLog) -> Result<()> {
+    let Some(dir) = &config::get().moderation.audit_dir else {
+        return Ok(());
+    };
+    let line = serde_json::to_string(log)
+        .context("[write_moderation_log] serializing json err.")?;
+    search_log::append_line(&LOG_FILE, dir, "moderation", &line).await
+        .context("[write_moderation_log] append_line err.")?;
+    Ok(())
+}
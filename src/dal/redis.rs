@@ -1,22 +1,66 @@
-use r2d2::Pool;
-use redis::{Client, Commands, SetOptions};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use r2d2::{CustomizeConnection, Pool, PooledConnection};
+use redis::{Client, Commands, Connection, ExistenceCheck, SetOptions};
 use tokio::sync::OnceCell;
 use anyhow::{Context, Result};
 use redis::SetExpiry::EX;
-use serde_json::Value;
+use crate::{config, metrics};
 
 static REDIS_CLIENT: OnceCell<Pool<Client>> = OnceCell::const_new();
 
-async fn get_redis_client() -> &'static Pool<Client> {
-    REDIS_CLIENT.get_or_init(|| async {
-        let client = Client::open("redis://127.0.0.1/").expect("Failed to create redis client");
-        let pool = Pool::builder().build(client).expect("Failed to create redis connection pool");
-        pool
+/// Bounds every command on a new connection, so a hung redis fails calls instead of holding them.
+#[derive(Debug)]
+struct CommandTimeout(Duration);
+
+impl CustomizeConnection<Connection, redis::RedisError> for CommandTimeout {
+    fn on_acquire(&self, con: &mut Connection) -> Result<(), redis::RedisError> {
+        con.set_read_timeout(Some(self.0))?;
+        con.set_write_timeout(Some(self.0))
+    }
+}
+
+/// Connections are opened on demand and replaced when broken, so an unreachable redis
+/// fails the calls rather than the pool.
+async fn get_redis_client() -> Result<&'static Pool<Client>> {
+    REDIS_CLIENT.get_or_try_init(|| async {
+        let client = Client::open("redis://127.0.0.1/")
+            .context("[get_redis_client] create redis client err.")?;
+        let timeout = Duration::from_millis(config::get().lifecycle.redis_timeout_ms.max(1));
+        Ok(Pool::builder()
+            .connection_timeout(timeout)
+            .connection_customizer(Box::new(CommandTimeout(timeout)))
+            .build_unchecked(client))
     }).await
 }
 
+/// Checks a connection out on the blocking pool, `get` waits up to `redis_timeout_ms` for a
+/// free or new connection and must not hold a runtime worker meanwhile.
+async fn connection() -> Result<PooledConnection<Client>> {
+    let pool = get_redis_client().await?.clone();
+    let con = tokio::task::spawn_blocking(move || pool.get()).await
+        .context("[connection] checkout task err.")??;
+    Ok(con)
+}
+
+pub async fn ping() -> Result<()> {
+    metrics::observe_dal("redis", "ping", do_ping()).await
+}
+
+async fn do_ping() -> Result<()> {
+    let mut con = connection().await
+        .context("[ping] Failed to get redis client")?;
+    redis::cmd("PING").query::<String>(&mut *con)
+        .context("[ping] redis ping err.")?;
+    Ok(())
+}
+
 pub async fn get_user_history(user_id:i64) -> Result<Vec<i64>> {
-    let mut con = get_redis_client().await.get()
+    metrics::observe_dal("redis", "get_user_history", do_get_user_history(user_id)).await
+}
+
+async fn do_get_user_history(user_id:i64) -> Result<Vec<i64>> {
+    let mut con = connection().await
         .context("[get_user_history] Failed to get redis client")?;
     let key = format!("item_history:{}", user_id);
     let history: Vec<(String, f64)> = con.zrevrange_withscores(key, 0, 4)
@@ -28,56 +72,417 @@ pub async fn get_user_history(user_id:i64) -> Result<Vec<i64>> {
     Ok(history_id)
 }
 
-pub async fn write_impression(user_id:i64,items:Vec<Value>) -> Result<()> {
-    let mut con = get_redis_client().await.get()
+pub async fn write_impression(user_id:i64, item_ids:&[i64]) -> Result<()> {
+    metrics::observe_dal("redis", "write_impression", do_write_impression(user_id, item_ids)).await
+}
+
+async fn do_write_impression(user_id:i64, item_ids:&[i64]) -> Result<()> {
+    if item_ids.is_empty() {
+        return Ok(());
+    }
+    let mut con = connection().await
         .context("[write_impression] Failed to get redis client")?;
     let key = format!("item_impression:{}", user_id);
     let mut cmd = redis::cmd("BF.INSERT");
     cmd.arg(key)
         .arg("CAPACITY")
-        .arg(1000);
-    for item in items.iter() {
-        cmd.arg(item.get("item_id").unwrap().as_i64().unwrap());
-    }
-    let _: Vec<bool> =cmd.query(&mut con)
+        .arg(1000)
+        .arg("ITEMS")
+        .arg(item_ids);
+    let _: Vec<bool> = cmd.query(&mut *con)
         .context("[write_impression] redis BF.INSERT err.")?;
     Ok(())
 }
 
-pub async fn execute_impression(user_id:i64,items:Vec<Value>) -> Result<Vec<Value>> {
-    let mut con = get_redis_client().await.get()
+/// Which of `item_ids` the user was already shown, per the `item_impression:{user_id}` bloom filter.
+pub async fn execute_impression(user_id:i64, item_ids:&[i64]) -> Result<Vec<bool>> {
+    metrics::observe_dal("redis", "execute_impression", do_execute_impression(user_id, item_ids)).await
+}
+
+async fn do_execute_impression(user_id:i64, item_ids:&[i64]) -> Result<Vec<bool>> {
+    if item_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut con = connection().await
         .context("[execute_impression] Failed to get redis client")?;
     let key = format!("item_impression:{}", user_id);
     let mut cmd = redis::cmd("BF.MEXISTS");
-    cmd.arg(key);
-    for item in items.iter() {
-        cmd.arg(item.get("item_id").unwrap().as_i64().unwrap());
-    }
-    let exists: Vec<bool> = cmd.query(&mut con)
+    cmd.arg(key)
+        .arg(item_ids);
+    let exists: Vec<bool> = cmd.query(&mut *con)
         .context("[execute_impression] redis BF.MEXISTS err.")?;
-    let mut filter_items = Vec::new();
-    for (item, exist) in items.into_iter().zip(exists) {
-        if !exist {
-            filter_items.push(item);
+    Ok(exists)
+}
+
+/// Raises each member's rank in `KEYS[1]` and stores its trend score in the `KEYS[2]` hash,
+/// unless another replica published a higher rank. `ARGV` is the ttl, then member, rank, score triples.
+const PUBLISH_HOTSPOTS: &str = r"
+for i = 2, #ARGV, 3 do
+    local current = redis.call('ZSCORE', KEYS[1], ARGV[i])
+    if not current or tonumber(ARGV[i + 1]) >= tonumber(current) then
+        redis.call('ZADD', KEYS[1], ARGV[i + 1], ARGV[i])
+        redis.call('HSET', KEYS[2], ARGV[i], ARGV[i + 2])
+    end
+end
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+redis.call('EXPIRE', KEYS[2], ARGV[1])
+return 0
+";
+
+/// Seconds the published hotspots outlive the last replica refreshing them.
+const HOTSPOT_TTL: u64 = 3600;
+
+/// Removes cooled off hotspots and publishes `ranked` as `(key, rank, trend score)`. Ranks order the
+/// `hotspot_rank:{namespace}` sorted set and only go up, so the replica that saw a key hottest wins, and
+/// the winner's trend score is kept in the `hotspot_score:{namespace}` hash. The per-key
+/// `hotspot:{namespace}:{key}` flags older readers check are still set while a key is hot.
+pub async fn publish_hotspots(namespace:&str, removed:&[String], ranked:&[(String, f64, f64)]) -> Result<()> {
+    metrics::observe_dal("redis", "publish_hotspots", do_publish_hotspots(namespace, removed, ranked)).await
+}
+
+async fn do_publish_hotspots(namespace:&str, removed:&[String], ranked:&[(String, f64, f64)]) -> Result<()> {
+    if removed.is_empty() && ranked.is_empty() {
+        return Ok(());
+    }
+    let mut con = connection().await
+        .context("[publish_hotspots] Failed to get redis client")?;
+    let rank_key = format!("hotspot_rank:{}", namespace);
+    let score_key = format!("hotspot_score:{}", namespace);
+    let flag_key = |key: &str| format!("hotspot:{}:{}", namespace, key);
+    let mut pipe = redis::pipe();
+    if !removed.is_empty() {
+        let flags: Vec<String> = removed.iter().map(|key| flag_key(key)).collect();
+        pipe.zrem(&rank_key, removed).ignore()
+            .hdel(&score_key, removed).ignore()
+            .del(flags).ignore();
+    }
+    if !ranked.is_empty() {
+        let script = redis::Script::new(PUBLISH_HOTSPOTS);
+        let mut invocation = script.key(&rank_key);
+        invocation.key(&score_key).arg(HOTSPOT_TTL);
+        for (key, rank, score) in ranked {
+            invocation.arg(key).arg(rank).arg(score);
+            pipe.set_options(flag_key(key), 1, SetOptions::default().with_expiration(EX(HOTSPOT_TTL))).ignore();
         }
+        let _: () = invocation.invoke(&mut *con)
+            .context("[publish_hotspots] redis publish script err.")?;
     }
-    Ok(filter_items)
+    let _: () = pipe.query(&mut con)
+        .context("[publish_hotspots] redis pipeline err.")?;
+    Ok(())
 }
 
-pub async fn set_hotspot(namespace:&str, key:&str) -> Result<()> {
-    let mut con = get_redis_client().await.get()
-        .context("[set_hotspot] Failed to get redis client")?;
-    let key = format!("hotspot:{}:{}", namespace,key);
-    let _: () = con.set_options(key, 1, SetOptions::default().with_expiration(EX(3600)))
-        .context("[set_hotspot] redis set err.")?;
-    Ok(())
+pub async fn set_topk(namespace:&str, topk:&str) -> Result<()> {
+    metrics::observe_dal("redis", "set_topk", do_set_topk(namespace, topk)).await
 }
 
-pub async fn set_topk(namespace:&str,topk:&str) -> Result<()> {
-    let mut con = get_redis_client().await.get()
+async fn do_set_topk(namespace:&str, topk:&str) -> Result<()> {
+    let mut con = connection().await
         .context("[set_topk] Failed to get redis client")?;
     let key = format!("topk:{}", namespace);
     let _: () = con.set(key, topk)
         .context("[set_topk] redis set err.")?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn get_similar_items(namespace:&str, item_id:i64) -> Result<Option<String>> {
+    metrics::observe_dal("redis", "get_similar_items", do_get_similar_items(namespace, item_id)).await
+}
+
+async fn do_get_similar_items(namespace:&str, item_id:i64) -> Result<Option<String>> {
+    let mut con = connection().await
+        .context("[get_similar_items] Failed to get redis client")?;
+    let key = format!("similar_items:{}:{}", namespace, item_id);
+    let items: Option<String> = con.get(key)
+        .context("[get_similar_items] redis get err.")?;
+    Ok(items)
+}
+
+pub async fn set_similar_items(namespace:&str, item_id:i64, items:&str, ttl:u64) -> Result<()> {
+    metrics::observe_dal("redis", "set_similar_items", do_set_similar_items(namespace, item_id, items, ttl)).await
+}
+
+async fn do_set_similar_items(namespace:&str, item_id:i64, items:&str, ttl:u64) -> Result<()> {
+    let mut con = connection().await
+        .context("[set_similar_items] Failed to get redis client")?;
+    let key = format!("similar_items:{}:{}", namespace, item_id);
+    let _: () = con.set_options(key, items, SetOptions::default().with_expiration(EX(ttl)))
+        .context("[set_similar_items] redis set err.")?;
+    Ok(())
+}
+
+pub async fn get_search_window(key:&str) -> Result<Option<String>> {
+    metrics::observe_dal("redis", "get_search_window", do_get_search_window(key)).await
+}
+
+async fn do_get_search_window(key:&str) -> Result<Option<String>> {
+    let mut con = connection().await
+        .context("[get_search_window] Failed to get redis client")?;
+    let key = format!("search_window:{}", key);
+    let window: Option<String> = con.get(key)
+        .context("[get_search_window] redis get err.")?;
+    Ok(window)
+}
+
+pub async fn set_search_window(key:&str, window:&str, ttl:u64) -> Result<()> {
+    metrics::observe_dal("redis", "set_search_window", do_set_search_window(key, window, ttl)).await
+}
+
+async fn do_set_search_window(key:&str, window:&str, ttl:u64) -> Result<()> {
+    let mut con = connection().await
+        .context("[set_search_window] Failed to get redis client")?;
+    let key = format!("search_window:{}", key);
+    let _: () = con.set_options(key, window, SetOptions::default().with_expiration(EX(ttl)))
+        .context("[set_search_window] redis set err.")?;
+    Ok(())
+}
+
+/// Adds `weight` to the query under each of its prefixes in the `suggest:{namespace}:{epoch}:{prefix}` sorted sets,
+/// keeping only the `keep` highest scored entries per prefix. A set expires `ttl` seconds after its last write.
+pub async fn record_query(namespace:&str, query:&str, epoch:i64, weight:f64, max_prefix_len:usize, keep:usize, ttl:i64) -> Result<()> {
+    metrics::observe_dal("redis", "record_query", do_record_query(namespace, query, epoch, weight, max_prefix_len, keep, ttl)).await
+}
+
+async fn do_record_query(namespace:&str, query:&str, epoch:i64, weight:f64, max_prefix_len:usize, keep:usize, ttl:i64) -> Result<()> {
+    let mut con = connection().await
+        .context("[record_query] Failed to get redis client")?;
+    let mut pipe = redis::pipe();
+    for (start, c) in query.char_indices().take(max_prefix_len) {
+        let key = format!("suggest:{}:{}:{}", namespace, epoch, &query[..start + c.len_utf8()]);
+        pipe.zincr(&key, query, weight).ignore()
+            .zremrangebyrank(&key, 0, -(keep as isize) - 1).ignore()
+            .expire(&key, ttl).ignore();
+    }
+    let _: () = pipe.query(&mut con)
+        .context("[record_query] redis pipeline err.")?;
+    Ok(())
+}
+
+/// Queries with their scores, highest first.
+pub type ScoredQueries = Vec<(String, f64)>;
+
+/// The `limit` highest scored queries under `prefix` with their scores, of weight epoch `epoch` and of the one before.
+pub async fn get_popular_queries(namespace:&str, prefix:&str, epoch:i64, limit:usize) -> Result<(ScoredQueries, ScoredQueries)> {
+    metrics::observe_dal("redis", "get_popular_queries", do_get_popular_queries(namespace, prefix, epoch, limit)).await
+}
+
+async fn do_get_popular_queries(namespace:&str, prefix:&str, epoch:i64, limit:usize) -> Result<(ScoredQueries, ScoredQueries)> {
+    let mut con = connection().await
+        .context("[get_popular_queries] Failed to get redis client")?;
+    let mut pipe = redis::pipe();
+    for epoch in [epoch, epoch - 1] {
+        pipe.zrevrange_withscores(format!("suggest:{}:{}:{}", namespace, epoch, prefix), 0, limit as isize - 1);
+    }
+    let queries: (ScoredQueries, ScoredQueries) = pipe.query(&mut con)
+        .context("[get_popular_queries] redis pipeline err.")?;
+    Ok(queries)
+}
+
+/// Hotspots starting with `prefix`, highest trend score first.
+pub async fn get_hotspots(namespace:&str, prefix:&str, limit:usize) -> Result<Vec<String>> {
+    metrics::observe_dal("redis", "get_hotspots", do_get_hotspots(namespace, prefix, limit)).await
+}
+
+async fn do_get_hotspots(namespace:&str, prefix:&str, limit:usize) -> Result<Vec<String>> {
+    let mut con = connection().await
+        .context("[get_hotspots] Failed to get redis client")?;
+    let key = format!("hotspot_rank:{}", namespace);
+    let hotspots: Vec<String> = con.zrevrange(key, 0, -1)
+        .context("[get_hotspots] redis zrevrange err.")?;
+    Ok(hotspots.into_iter()
+        .filter(|hotspot| hotspot.starts_with(prefix))
+        .take(limit)
+        .collect())
+}
+
+/// Stores the replica's snapshot as one field of the `hotspot_snapshots:{namespace}` hash,
+/// which expires `ttl` after the last replica saved.
+pub async fn save_hotspot_snapshot(namespace:&str, replica:&str, snapshot:&str, ttl:i64) -> Result<()> {
+    metrics::observe_dal("redis", "save_hotspot_snapshot", do_save_hotspot_snapshot(namespace, replica, snapshot, ttl)).await
+}
+
+async fn do_save_hotspot_snapshot(namespace:&str, replica:&str, snapshot:&str, ttl:i64) -> Result<()> {
+    let mut con = connection().await
+        .context("[save_hotspot_snapshot] Failed to get redis client")?;
+    let key = format!("hotspot_snapshots:{}", namespace);
+    let _: () = redis::pipe()
+        .hset(&key, replica, snapshot).ignore()
+        .expire(&key, ttl).ignore()
+        .sadd("hotspot_namespaces", namespace).ignore()
+        .query(&mut con)
+        .context("[save_hotspot_snapshot] redis hset err.")?;
+    Ok(())
+}
+
+/// Every replica's snapshot of the namespace, keyed by replica.
+pub async fn load_hotspot_snapshots(namespace:&str) -> Result<HashMap<String, String>> {
+    metrics::observe_dal("redis", "load_hotspot_snapshots", do_load_hotspot_snapshots(namespace)).await
+}
+
+async fn do_load_hotspot_snapshots(namespace:&str) -> Result<HashMap<String, String>> {
+    let mut con = connection().await
+        .context("[load_hotspot_snapshots] Failed to get redis client")?;
+    let key = format!("hotspot_snapshots:{}", namespace);
+    let snapshots: HashMap<String, String> = con.hgetall(key)
+        .context("[load_hotspot_snapshots] redis hgetall err.")?;
+    Ok(snapshots)
+}
+
+pub async fn remove_hotspot_snapshots(namespace:&str, replicas:&[String]) -> Result<()> {
+    metrics::observe_dal("redis", "remove_hotspot_snapshots", do_remove_hotspot_snapshots(namespace, replicas)).await
+}
+
+async fn do_remove_hotspot_snapshots(namespace:&str, replicas:&[String]) -> Result<()> {
+    if replicas.is_empty() {
+        return Ok(());
+    }
+    let mut con = connection().await
+        .context("[remove_hotspot_snapshots] Failed to get redis client")?;
+    let key = format!("hotspot_snapshots:{}", namespace);
+    let _: () = con.hdel(key, replicas)
+        .context("[remove_hotspot_snapshots] redis hdel err.")?;
+    Ok(())
+}
+
+pub async fn get_hotspot_namespaces() -> Result<Vec<String>> {
+    metrics::observe_dal("redis", "get_hotspot_namespaces", do_get_hotspot_namespaces()).await
+}
+
+async fn do_get_hotspot_namespaces() -> Result<Vec<String>> {
+    let mut con = connection().await
+        .context("[get_hotspot_namespaces] Failed to get redis client")?;
+    let namespaces: Vec<String> = con.smembers("hotspot_namespaces")
+        .context("[get_hotspot_namespaces] redis smembers err.")?;
+    Ok(namespaces)
+}
+
+/// Returns true for the one replica that gets to announce the hotspot.
+pub async fn claim_hotspot(namespace:&str, key:&str, ttl:u64) -> Result<bool> {
+    metrics::observe_dal("redis", "claim_hotspot", do_claim_hotspot(namespace, key, ttl)).await
+}
+
+async fn do_claim_hotspot(namespace:&str, key:&str, ttl:u64) -> Result<bool> {
+    let mut con = connection().await
+        .context("[claim_hotspot] Failed to get redis client")?;
+    let key = format!("hotspot_announced:{}:{}", namespace, key);
+    let claimed: Option<String> = con.set_options(key, 1, SetOptions::default()
+        .conditional_set(ExistenceCheck::NX)
+        .with_expiration(EX(ttl)))
+        .context("[claim_hotspot] redis set err.")?;
+    Ok(claimed.is_some())
+}
+
+/// Counts a hit in the current time bucket and returns the sum over the last `buckets` buckets.
+pub async fn incr_hotspot_count(namespace:&str, key:&str, now:i64, bucket_secs:i64, buckets:usize) -> Result<u32> {
+    metrics::observe_dal("redis", "incr_hotspot_count", do_incr_hotspot_count(namespace, key, now, bucket_secs, buckets)).await
+}
+
+async fn do_incr_hotspot_count(namespace:&str, key:&str, now:i64, bucket_secs:i64, buckets:usize) -> Result<u32> {
+    let mut con = connection().await
+        .context("[incr_hotspot_count] Failed to get redis client")?;
+    let current = now / bucket_secs;
+    let bucket_key = |index: i64| format!("hotspot_count:{}:{}:{}", namespace, key, index);
+    let window_keys: Vec<String> = (0..buckets as i64).map(|i| bucket_key(current - i)).collect();
+    let (counts,): (Vec<Option<u32>>,) = redis::pipe()
+        .incr(bucket_key(current), 1).ignore()
+        .expire(bucket_key(current), bucket_secs * (buckets as i64 + 1)).ignore()
+        .mget(&window_keys)
+        .query(&mut con)
+        .context("[incr_hotspot_count] redis pipeline err.")?;
+    Ok(counts.into_iter().flatten().sum())
+}
+
+/// The canonical event an alias was merged into, if any.
+pub async fn get_event_canonical(collection:&str, event:&str) -> Result<Option<String>> {
+    metrics::observe_dal("redis", "get_event_canonical", do_get_event_canonical(collection, event)).await
+}
+
+async fn do_get_event_canonical(collection:&str, event:&str) -> Result<Option<String>> {
+    let mut con = connection().await
+        .context("[get_event_canonical] Failed to get redis client")?;
+    let key = format!("event_canonical:{}", collection);
+    let canonical: Option<String> = con.hget(key, event)
+        .context("[get_event_canonical] redis hget err.")?;
+    Ok(canonical)
+}
+
+pub async fn get_event_aliases(collection:&str, canonical:&str) -> Result<Vec<String>> {
+    metrics::observe_dal("redis", "get_event_aliases", do_get_event_aliases(collection, canonical)).await
+}
+
+async fn do_get_event_aliases(collection:&str, canonical:&str) -> Result<Vec<String>> {
+    let mut con = connection().await
+        .context("[get_event_aliases] Failed to get redis client")?;
+    let key = format!("event_aliases:{}:{}", collection, canonical);
+    let aliases: Vec<String> = con.smembers(key)
+        .context("[get_event_aliases] redis smembers err.")?;
+    Ok(aliases)
+}
+
+/// Points each alias, and everything that was merged into it, at `canonical`.
+pub async fn add_event_aliases(collection:&str, canonical:&str, aliases:&[String]) -> Result<()> {
+    metrics::observe_dal("redis", "add_event_aliases", do_add_event_aliases(collection, canonical, aliases)).await
+}
+
+async fn do_add_event_aliases(collection:&str, canonical:&str, aliases:&[String]) -> Result<()> {
+    if aliases.is_empty() {
+        return Ok(());
+    }
+    let mut con = connection().await
+        .context("[add_event_aliases] Failed to get redis client")?;
+    let canonical_key = format!("event_canonical:{}", collection);
+    let aliases_key = format!("event_aliases:{}:{}", collection, canonical);
+    let mut pipe = redis::pipe();
+    for alias in aliases {
+        let merged: Vec<String> = con.smembers(format!("event_aliases:{}:{}", collection, alias))
+            .context("[add_event_aliases] redis smembers err.")?;
+        for name in merged.iter().chain(std::iter::once(alias)) {
+            pipe.hset(&canonical_key, name, canonical).ignore()
+                .sadd(&aliases_key, name).ignore();
+        }
+        pipe.del(format!("event_aliases:{}:{}", collection, alias)).ignore();
+    }
+    let _: () = pipe.query(&mut con)
+        .context("[add_event_aliases] redis pipeline err.")?;
+    Ok(())
+}
+
+/// Forgets deleted canonical events together with every alias pointing at them.
+pub async fn remove_event_aliases(collection:&str, canonicals:&[String]) -> Result<()> {
+    metrics::observe_dal("redis", "remove_event_aliases", do_remove_event_aliases(collection, canonicals)).await
+}
+
+async fn do_remove_event_aliases(collection:&str, canonicals:&[String]) -> Result<()> {
+    if canonicals.is_empty() {
+        return Ok(());
+    }
+    let mut con = connection().await
+        .context("[remove_event_aliases] Failed to get redis client")?;
+    let canonical_key = format!("event_canonical:{}", collection);
+    let mut pipe = redis::pipe();
+    for canonical in canonicals {
+        let aliases_key = format!("event_aliases:{}:{}", collection, canonical);
+        let aliases: Vec<String> = con.smembers(&aliases_key)
+            .context("[remove_event_aliases] redis smembers err.")?;
+        if !aliases.is_empty() {
+            pipe.hdel(&canonical_key, aliases).ignore();
+        }
+        pipe.del(aliases_key).ignore();
+    }
+    let _: () = pipe.query(&mut con)
+        .context("[remove_event_aliases] redis pipeline err.")?;
+    Ok(())
+}
+
+/// Item ids operators have blocked from every output.
+pub async fn get_blocked_items() -> Result<HashSet<i64>> {
+    metrics::observe_dal("redis", "get_blocked_items", do_get_blocked_items()).await
+}
+
+async fn do_get_blocked_items() -> Result<HashSet<i64>> {
+    let mut con = connection().await
+        .context("[get_blocked_items] Failed to get redis client")?;
+    let items: HashSet<i64> = con.smembers("blocked_items")
+        .context("[get_blocked_items] redis smembers err.")?;
+    Ok(items)
+}
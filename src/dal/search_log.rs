@@ -0,0 +1,109 @@
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use time::{Date, OffsetDateTime};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use crate::config::{self, SearchLogSink};
+use crate::dal::kafka;
+
+static LOG_FILE: Mutex<Option<(Date, File)>> = Mutex::const_new(None);
+
+#[derive(Serialize)]
+pub struct SearchResultLog {
+    pub item_id: i64,
+    /// 1-based position across pages.
+    pub position: usize,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchLog {
+    Search {
+        request_id: String,
+        namespace: String,
+        user_id: i64,
+        query: String,
+        processed_query: String,
+        page: i64,
+        results: Vec<SearchResultLog>,
+        timestamp: i64,
+    },
+    Click {
+        request_id: String,
+        namespace: String,
+        user_id: i64,
+        item_id: i64,
+        position: i32,
+        timestamp: i64,
+    },
+}
+
+impl SearchLog {
+    fn request_id(&self) -> &str {
+        match self {
+            SearchLog::Search { request_id, .. } | SearchLog::Click { request_id, .. } => request_id,
+        }
+    }
+}
+
+/// Writes one JSON line to the configured sink, keyed by request id so clicks land next to their search.
+pub async fn write_search_log(log: &SearchLog) -> Result<()> {
+    let conf = &config::get().search_log;
+    let line = serde_json::to_string(log)
+        .context("[write_search_log] serializing json err.")?;
+    match conf.sink {
+        SearchLogSink::None => {}
+        SearchLogSink::Kafka => {
+            kafka::send_search_log(&conf.topic, log.request_id(), &line).await
+                .context("[write_search_log] send_search_log err.")?;
+        }
+        SearchLogSink::File => {
+            append_line(&LOG_FILE, &conf.dir, "search", &line).await
+                .context("[write_search_log] append_line err.")?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends to `{dir}/{prefix}-{date}.log`, reopening `log_file` when the day changes.
+/// File io runs on tokio's blocking pool, the lock only orders the lines.
+pub async fn append_line(log_file: &Mutex<Option<(Date, File)>>, dir: &str, prefix: &str, line: &str) -> Result<()> {
+    let today = OffsetDateTime::now_utc().date();
+    let mut guard = log_file.lock().await;
+    if guard.as_ref().is_none_or(|(date, _)| *date != today) {
+        fs::create_dir_all(dir).await
+            .context("[append_line] create log dir err.")?;
+        let path = Path::new(dir).join(format!("{}-{}.log", prefix, today));
+        let file = OpenOptions::new().create(true).append(true).open(&path).await
+            .with_context(|| format!("[append_line] open {} err.", path.display()))?;
+        *guard = Some((today, file));
+    }
+    if let Some((_, file)) = guard.as_mut() {
+        let line = format!("{}\n", line);
+        file.write_all(line.as_bytes()).await
+            .context("[append_line] write log err.")?;
+        file.flush().await
+            .context("[append_line] flush log err.")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn append_line_writes_one_line_per_call() {
+        static TEST_FILE: Mutex<Option<(Date, File)>> = Mutex::const_new(None);
+        let dir = std::env::temp_dir().join(format!("search_log_test_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        append_line(&TEST_FILE, dir, "search", "{\"a\":1}").await.unwrap();
+        append_line(&TEST_FILE, dir, "search", "{\"a\":2}").await.unwrap();
+        let path = Path::new(dir).join(format!("search-{}.log", OffsetDateTime::now_utc().date()));
+        let written = fs::read_to_string(&path).await.unwrap();
+        fs::remove_dir_all(dir).await.unwrap();
+        assert_eq!(written, "{\"a\":1}\n{\"a\":2}\n");
+    }
+}
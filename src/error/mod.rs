@@ -0,0 +1,117 @@
+use std::fmt;
+use tonic::Code;
+use crate::common::{BaseResp, StatusCode};
+
+/// Failures the caller can act on. Raised anywhere inside an anyhow chain and mapped to a
+/// `StatusCode` at the rpc boundary, everything else is reported as `Server_Error`.
+///
+/// Rpcs answer with an ok grpc status either way, callers read the outcome from `BaseResp`.
+/// The matching grpc code only classifies the rpc span.
+#[derive(Debug)]
+pub enum ServiceError {
+    /// The request itself is invalid, the message names the offending field.
+    Param(String),
+    /// A referenced item does not exist. Users have no registry: one without history is a
+    /// cold start and gets recommendations, not `NotFound`.
+    NotFound(String),
+    OverFrequency(String),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Param(msg) | ServiceError::NotFound(msg) | ServiceError::OverFrequency(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl ServiceError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::Param(_) => StatusCode::ParamError,
+            ServiceError::NotFound(_) => StatusCode::NotFoundError,
+            ServiceError::OverFrequency(_) => StatusCode::OverFrequencyError,
+        }
+    }
+
+    pub fn grpc_code(&self) -> Code {
+        match self {
+            ServiceError::Param(_) => Code::InvalidArgument,
+            ServiceError::NotFound(_) => Code::NotFound,
+            ServiceError::OverFrequency(_) => Code::ResourceExhausted,
+        }
+    }
+}
+
+fn service_error(e: &anyhow::Error) -> Option<&ServiceError> {
+    e.chain().find_map(|cause| cause.downcast_ref::<ServiceError>())
+}
+
+/// The grpc code matching a handler error, `Internal` for an unclassified one.
+pub fn grpc_code(e: &anyhow::Error) -> Code {
+    service_error(e).map_or(Code::Internal, ServiceError::grpc_code)
+}
+
+pub fn success() -> BaseResp {
+    BaseResp {
+        status_code: StatusCode::Success as i32,
+        status_message: "Success".to_string(),
+    }
+}
+
+/// The `BaseResp` of a classified error with its own message. Unclassified errors become
+/// `Server_Error` without the chain, which only goes to the log.
+pub fn error_resp(rpc: &str, e: anyhow::Error) -> BaseResp {
+    match service_error(&e) {
+        Some(service_error) => {
+            tracing::warn!("[{}] rejected. err = {:#}", rpc, e);
+            BaseResp {
+                status_code: service_error.status_code() as i32,
+                status_message: service_error.to_string(),
+            }
+        }
+        None => {
+            tracing::error!("[{}] internal err. err = {:?}", rpc, e);
+            BaseResp {
+                status_code: StatusCode::ServerError as i32,
+                status_message: "internal error".to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+    use super::*;
+
+    #[test]
+    fn classified_errors_keep_their_code_through_context() {
+        let e = Err::<(), _>(ServiceError::NotFound("item 7 not found".to_string()))
+            .context("[handler] lookup err.")
+            .unwrap_err();
+        let resp = error_resp("test", e);
+        assert_eq!(resp.status_code, StatusCode::NotFoundError as i32);
+        assert_eq!(resp.status_message, "item 7 not found");
+    }
+
+    #[test]
+    fn grpc_codes_follow_the_classified_error() {
+        let not_found = Err::<(), _>(ServiceError::NotFound("item 7 not found".to_string()))
+            .context("[handler] lookup err.")
+            .unwrap_err();
+        assert_eq!(grpc_code(&not_found), Code::NotFound);
+        assert_eq!(grpc_code(&ServiceError::Param("page".to_string()).into()), Code::InvalidArgument);
+        assert_eq!(grpc_code(&ServiceError::OverFrequency("qps".to_string()).into()), Code::ResourceExhausted);
+        assert_eq!(grpc_code(&anyhow!("redis down")), Code::Internal);
+    }
+
+    #[test]
+    fn unclassified_errors_are_server_errors_without_details() {
+        let resp = error_resp("test", anyhow!("redis password is hunter2"));
+        assert_eq!(resp.status_code, StatusCode::ServerError as i32);
+        assert_eq!(resp.status_message, "internal error");
+    }
+}
@@ -0,0 +1,461 @@
+pub mod prefilter;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context, Result};
+use tokio::time::{interval, Duration, Instant};
+use crate::config::{self, ClusterConfig};
+use crate::dal::{milvus, redis};
+use crate::dal::milvus::EventRow;
+
+/// Writes not yet sent to one event collection.
+#[derive(Default)]
+struct PendingEvents {
+    inserts: HashMap<String, EventRow>,
+    /// `event_name -> (hits, last_seen_at)` for events already stored.
+    hits: HashMap<String, (i64, i64)>,
+}
+
+impl PendingEvents {
+    fn record_hits(&mut self, event: &str, hits: i64, now: i64) {
+        if let Some(row) = self.inserts.get_mut(event) {
+            row.hit_count += hits;
+            row.last_seen_at = row.last_seen_at.max(now);
+            return;
+        }
+        let entry = self.hits.entry(event.to_string()).or_insert((0, now));
+        entry.0 += hits;
+        entry.1 = entry.1.max(now);
+    }
+
+    /// Moves the hits buffered for `from` onto `to`, once `from` was merged into `to`.
+    fn move_hits(&mut self, from: &[String], to: &str) {
+        for event in from {
+            if let Some((hits, last_seen)) = self.hits.remove(event) {
+                self.record_hits(to, hits, last_seen);
+            }
+        }
+    }
+
+    /// Puts writes that failed back in front of whatever was buffered meanwhile.
+    fn requeue(&mut self, failed: PendingEvents) {
+        for (event, row) in failed.inserts {
+            match self.inserts.get_mut(&event) {
+                Some(buffered) => {
+                    buffered.hit_count += row.hit_count;
+                    buffered.created_at = buffered.created_at.min(row.created_at);
+                }
+                None => {
+                    self.inserts.insert(event, row);
+                }
+            }
+        }
+        for (event, (hits, last_seen)) in failed.hits {
+            self.record_hits(&event, hits, last_seen);
+        }
+    }
+}
+
+static PENDING_EVENTS: LazyLock<Mutex<HashMap<String, PendingEvents>>> = LazyLock::new(Default::default);
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn record_hits(collection: &str, event: &str, hits: i64, now: i64) {
+    let mut pending = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    pending.entry(collection.to_string()).or_default().record_hits(event, hits, now);
+}
+
+/// The buffered event most similar to `embedding` at or above `threshold`, counting the hit on it.
+fn match_pending(collection: &str, event: &str, embedding: &[f32], threshold: f32, now: i64) -> Option<String> {
+    let mut pending = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    let pending = pending.get_mut(collection)?;
+    let nearest = if pending.inserts.contains_key(event) {
+        event.to_string()
+    } else {
+        pending.inserts.values()
+            .map(|row| (row, inner_product(&row.embedding, embedding)))
+            .filter(|(_, score)| *score >= threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(row, _)| row.event_name.clone())?
+    };
+    pending.record_hits(&nearest, 1, now);
+    Some(nearest)
+}
+
+/// Same metric as the event collections' IP index.
+fn inner_product(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Buffers a new event, returning whether the collection reached `max_pending`.
+/// False when the event is already buffered, its hit is counted instead.
+fn buffer_insert(collection: &str, event: &str, embedding: Vec<f32>, now: i64) -> bool {
+    let mut pending = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    let pending = pending.entry(collection.to_string()).or_default();
+    if pending.inserts.contains_key(event) {
+        pending.record_hits(event, 1, now);
+        return false;
+    }
+    pending.inserts.insert(event.to_string(), EventRow {
+        event_name: event.to_string(),
+        embedding,
+        created_at: now,
+        last_seen_at: now,
+        hit_count: 1,
+    });
+    pending.inserts.len() >= config::get().event.max_pending
+}
+
+/// Maps a distilled event onto the canonical event of its cluster, inserting it as a new
+/// canonical event when nothing stored is similar enough.
+pub async fn resolve_event(namespace: &str, event: &str, embedding: Vec<f32>) -> Result<String> {
+    let conf = config::get().event.cluster(namespace);
+    let now = now();
+    if let Some(canonical) = redis::get_event_canonical(&conf.collection, event).await
+        .context("[resolve_event] get_event_canonical err.")? {
+        record_hits(&conf.collection, &canonical, 1, now);
+        return Ok(canonical);
+    }
+
+    let neighbors = milvus::recall_event(&conf.collection, embedding.clone(), conf.top_k).await
+        .context("[resolve_event] recall_event err.")?;
+    if let Some((nearest, _)) = neighbors.into_iter().find(|(_, score)| *score >= conf.match_threshold) {
+        // a stale neighbor may itself have been merged away since it was indexed
+        let canonical = redis::get_event_canonical(&conf.collection, &nearest).await
+            .context("[resolve_event] get_event_canonical err.")?
+            .unwrap_or(nearest);
+        if canonical != event {
+            redis::add_event_aliases(&conf.collection, &canonical, &[event.to_string()]).await
+                .context("[resolve_event] add_event_aliases err.")?;
+        }
+        record_hits(&conf.collection, &canonical, 1, now);
+        return Ok(canonical);
+    }
+
+    // events waiting in the buffer are not indexed yet, recall cannot see them
+    if let Some(pending) = match_pending(&conf.collection, event, &embedding, conf.match_threshold, now) {
+        if pending != event {
+            redis::add_event_aliases(&conf.collection, &pending, &[event.to_string()]).await
+                .context("[resolve_event] add_event_aliases err.")?;
+        }
+        return Ok(pending);
+    }
+    if buffer_insert(&conf.collection, event, embedding, now) {
+        flush_collection(&conf.collection).await
+            .context("[resolve_event] flush_collection err.")?;
+    }
+    Ok(event.to_string())
+}
+
+/// Writes every buffered event and hit. A failing collection does not stop the others,
+/// the error names every collection that failed.
+pub async fn flush_events() -> Result<()> {
+    let collections: Vec<String> = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner())
+        .keys().cloned().collect();
+    flush_all(collections, |collection| async move { flush_collection(&collection).await }).await
+}
+
+async fn flush_all<F, Fut>(collections: Vec<String>, mut flush: F) -> Result<()>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut failed = Vec::new();
+    for collection in collections {
+        if let Err(e) = flush(collection.clone()).await {
+            tracing::error!("[flush_events] flush_collection err. collection = {}, err = {:?}", collection, e);
+            failed.push(collection);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("[flush_events] flush_collection failed for {:?}", failed);
+    }
+    Ok(())
+}
+
+async fn flush_collection(collection: &str) -> Result<()> {
+    let pending = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner())
+        .remove(collection)
+        .unwrap_or_default();
+    if pending.inserts.is_empty() && pending.hits.is_empty() {
+        return Ok(());
+    }
+    let rows: Vec<EventRow> = pending.inserts.into_values().collect();
+    let hits = pending.hits;
+    if let Err(e) = milvus::insert_events(collection, &rows).await {
+        let inserts = rows.into_iter().map(|row| (row.event_name.clone(), row)).collect();
+        requeue(collection, PendingEvents { inserts, hits });
+        return Err(e).context("[flush_collection] insert_events err.");
+    }
+    if let Err(e) = milvus::touch_events(collection, &hits).await {
+        requeue(collection, PendingEvents { inserts: HashMap::new(), hits });
+        return Err(e).context("[flush_collection] touch_events err.");
+    }
+    tracing::info!("[flush_collection] flushed. collection = {}, inserted = {}, touched = {}",
+        collection, rows.len(), hits.len());
+    Ok(())
+}
+
+fn requeue(collection: &str, failed: PendingEvents) {
+    let mut pending = PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    pending.entry(collection.to_string()).or_default().requeue(failed);
+}
+
+pub async fn start_event_flusher() -> ! {
+    let mut ticker = interval(Duration::from_secs(config::get().event.flush_interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        if let Err(e) = flush_events().await {
+            tracing::error!("[start_event_flusher] flush_events err. err = {:?}", e);
+        }
+    }
+}
+
+/// Distinct collections across the default and per-namespace clusters, first config wins.
+fn collection_clusters() -> Vec<&'static ClusterConfig> {
+    let event_conf = &config::get().event;
+    let mut seen = HashSet::new();
+    std::iter::once(&event_conf.default_cluster)
+        .chain(event_conf.clusters.values())
+        .filter(|conf| seen.insert(conf.collection.as_str()))
+        .collect()
+}
+
+/// Fails unless every configured event collection is loaded.
+pub async fn check_collections() -> Result<()> {
+    for conf in collection_clusters() {
+        milvus::check_event_collection(&conf.collection).await
+            .context("[check_collections] check_event_collection err.")?;
+    }
+    Ok(())
+}
+
+/// Periodically deletes events idle longer than `max_idle_days`.
+pub async fn start_event_compactor() -> ! {
+    let mut ticker = interval(Duration::from_secs(config::get().event.compaction_interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        for conf in collection_clusters() {
+            if conf.max_idle_days <= 0 {
+                continue;
+            }
+            match compact_events(conf).await {
+                Ok(deleted) => {
+                    tracing::info!("[start_event_compactor] compacted. collection = {}, deleted = {}", conf.collection, deleted);
+                }
+                Err(e) => {
+                    tracing::error!("[start_event_compactor] compact_events err. collection = {}, err = {:?}", conf.collection, e);
+                }
+            }
+        }
+    }
+}
+
+async fn compact_events(conf: &ClusterConfig) -> Result<usize> {
+    // pending hits may keep an event alive, write them before judging idleness
+    flush_collection(&conf.collection).await
+        .context("[compact_events] flush_collection err.")?;
+    let before = now() - conf.max_idle_days * 86400;
+    let filter = format!("last_seen_at < {}", before);
+    let budget = Duration::from_secs(config::get().event.compaction_budget_secs.max(1));
+    delete_in_batches(conf.merge_batch, budget,
+        || milvus::query_events(&conf.collection, &filter, conf.merge_batch),
+        |names| async move {
+            milvus::delete_events(&conf.collection, &names).await
+                .context("[compact_events] delete_events err.")?;
+            redis::remove_event_aliases(&conf.collection, &names).await
+                .context("[compact_events] remove_event_aliases err.")
+        }).await
+}
+
+/// Deletes the fetched events batch after batch until a batch comes back short, holds nothing
+/// new, or `budget` runs out. Returns how many were deleted.
+async fn delete_in_batches<F, FFut, D, DFut>(batch: usize, budget: Duration, mut fetch: F, mut delete: D) -> Result<usize>
+where
+    F: FnMut() -> FFut,
+    FFut: Future<Output = Result<Vec<EventRow>>>,
+    D: FnMut(Vec<String>) -> DFut,
+    DFut: Future<Output = Result<()>>,
+{
+    let deadline = Instant::now() + budget;
+    let mut deleted: HashSet<String> = HashSet::new();
+    loop {
+        let rows = fetch().await
+            .context("[delete_in_batches] query_events err.")?;
+        let fetched = rows.len();
+        // a delete may not be visible to the next query yet
+        let names: Vec<String> = rows.into_iter()
+            .map(|row| row.event_name)
+            .filter(|name| !deleted.contains(name))
+            .collect();
+        if names.is_empty() {
+            break;
+        }
+        delete(names.clone()).await?;
+        deleted.extend(names);
+        if fetched < batch || Instant::now() >= deadline {
+            break;
+        }
+    }
+    Ok(deleted.len())
+}
+
+/// Periodically folds near-duplicate events already stored in each configured collection.
+pub async fn start_event_merger() -> ! {
+    let mut ticker = interval(Duration::from_secs(config::get().event.merge_interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        for conf in collection_clusters() {
+            match merge_events(conf).await {
+                Ok(merged) => {
+                    tracing::info!("[start_event_merger] merged. collection = {}, merged = {}", conf.collection, merged);
+                }
+                Err(e) => {
+                    tracing::error!("[start_event_merger] merge_events err. collection = {}, err = {:?}", conf.collection, e);
+                }
+            }
+        }
+    }
+}
+
+/// Events that already collect aliases win as canonical, the rest in scan order.
+async fn merge_events(conf: &ClusterConfig) -> Result<usize> {
+    let events = milvus::query_events(&conf.collection, "", conf.merge_batch).await
+        .context("[merge_events] query_events err.")?;
+    let stats: HashMap<String, (i64, i64)> = events.iter()
+        .map(|row| (row.event_name.clone(), (row.hit_count, row.last_seen_at)))
+        .collect();
+    let mut ranked = Vec::with_capacity(events.len());
+    for row in events {
+        let aliases = redis::get_event_aliases(&conf.collection, &row.event_name).await
+            .context("[merge_events] get_event_aliases err.")?;
+        ranked.push((aliases.len(), row.event_name, row.embedding));
+    }
+    ranked.sort_by_key(|(aliases, _, _)| std::cmp::Reverse(*aliases));
+
+    let mut merged_away: HashSet<String> = HashSet::new();
+    for (_, canonical, embedding) in ranked {
+        if merged_away.contains(&canonical) {
+            continue;
+        }
+        let neighbors = milvus::recall_event(&conf.collection, embedding, conf.top_k).await
+            .context("[merge_events] recall_event err.")?;
+        let duplicates: Vec<String> = neighbors.into_iter()
+            .filter(|(name, score)| *score >= conf.merge_threshold && *name != canonical && !merged_away.contains(name))
+            .map(|(name, _)| name)
+            .collect();
+        if duplicates.is_empty() {
+            continue;
+        }
+        redis::add_event_aliases(&conf.collection, &canonical, &duplicates).await
+            .context("[merge_events] add_event_aliases err.")?;
+        milvus::delete_events(&conf.collection, &duplicates).await
+            .context("[merge_events] delete_events err.")?;
+        // duplicates outside this batch have unknown stats and only lend their name
+        for duplicate in &duplicates {
+            if let Some(&(hits, last_seen)) = stats.get(duplicate) {
+                record_hits(&conf.collection, &canonical, hits, last_seen);
+            }
+        }
+        // hits buffered before the aliases existed would otherwise be written to deleted rows
+        PENDING_EVENTS.lock().unwrap_or_else(|e| e.into_inner())
+            .entry(conf.collection.clone()).or_default()
+            .move_hits(&duplicates, &canonical);
+        tracing::info!("[merge_events] merged. canonical = {}, aliases = {:?}", canonical, duplicates);
+        merged_away.extend(duplicates);
+        // the canonical itself must not be merged into a later event
+        merged_away.insert(canonical);
+    }
+    Ok(merged_away.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_hits(collection: &str, event: &str) -> i64 {
+        PENDING_EVENTS.lock().unwrap().get(collection).unwrap().inserts[event].hit_count
+    }
+
+    #[test]
+    fn buffered_event_absorbs_similar_events() {
+        let collection = "test_buffered_event_absorbs_similar_events";
+        buffer_insert(collection, "phone launch", vec![1.0, 0.0], 100);
+        assert_eq!(match_pending(collection, "new phone", &[0.9, 0.1], 0.8, 101).as_deref(), Some("phone launch"));
+        assert_eq!(match_pending(collection, "phone launch", &[0.0, 1.0], 0.8, 102).as_deref(), Some("phone launch"));
+        assert_eq!(pending_hits(collection, "phone launch"), 3);
+    }
+
+    #[tokio::test]
+    async fn flush_all_keeps_going_past_a_failure() {
+        let mut flushed = Vec::new();
+        let result = flush_all(vec!["a".to_string(), "b".to_string(), "c".to_string()], |collection| {
+            flushed.push(collection.clone());
+            async move {
+                if collection == "b" { bail!("milvus down") } else { Ok(()) }
+            }
+        }).await;
+        assert_eq!(flushed, ["a", "b", "c"]);
+        assert!(result.unwrap_err().to_string().contains("[\"b\"]"));
+    }
+
+    fn rows(names: &[&str]) -> Vec<EventRow> {
+        names.iter().map(|name| EventRow {
+            event_name: name.to_string(),
+            embedding: Vec::new(),
+            created_at: 0,
+            last_seen_at: 0,
+            hit_count: 0,
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn compaction_continues_until_a_short_batch() {
+        let mut batches = vec![rows(&["e"]), rows(&["c", "d"]), rows(&["a", "b"])];
+        let mut deleted = Vec::new();
+        let count = delete_in_batches(2, Duration::from_secs(60),
+            || std::future::ready(Ok(batches.pop().unwrap_or_default())),
+            |names| {
+                deleted.extend(names);
+                std::future::ready(Ok(()))
+            }).await.unwrap();
+        assert_eq!(count, 5);
+        assert_eq!(deleted, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[tokio::test]
+    async fn compaction_stops_on_rows_already_deleted() {
+        let mut fetches = 0;
+        let count = delete_in_batches(2, Duration::from_secs(60),
+            || {
+                fetches += 1;
+                std::future::ready(Ok(rows(&["a", "b"])))
+            },
+            |_| std::future::ready(Ok(()))).await.unwrap();
+        assert_eq!((count, fetches), (2, 2));
+    }
+
+    #[test]
+    fn merged_events_hand_their_pending_hits_to_the_canonical() {
+        let mut pending = PendingEvents::default();
+        pending.record_hits("dup", 3, 50);
+        pending.record_hits("canonical", 1, 40);
+        pending.record_hits("other", 2, 10);
+        pending.move_hits(&["dup".to_string()], "canonical");
+        assert_eq!(pending.hits.get("canonical"), Some(&(4, 50)));
+        assert!(!pending.hits.contains_key("dup"));
+        assert_eq!(pending.hits.get("other"), Some(&(2, 10)));
+    }
+
+    #[test]
+    fn dissimilar_events_are_not_matched() {
+        let collection = "test_dissimilar_events_are_not_matched";
+        buffer_insert(collection, "phone launch", vec![1.0, 0.0], 100);
+        assert_eq!(match_pending(collection, "flood", &[0.0, 1.0], 0.8, 101), None);
+        assert_eq!(match_pending("test_empty_collection", "flood", &[0.0, 1.0], 0.8, 101), None);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use crate::config;
+use crate::dal::model::ExtractedEvent;
+use crate::query;
+
+static WORD_LISTS: OnceLock<WordLists> = OnceLock::new();
+
+struct WordLists {
+    stopwords: HashSet<String>,
+    blocklist: Vec<String>,
+}
+
+fn get_word_lists() -> &'static WordLists {
+    WORD_LISTS.get_or_init(|| {
+        let conf = &config::get().event.prefilter;
+        let normalized = |path: Option<&str>| query::read_lines(path)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|line| query::normalize(&line))
+            .filter(|line| !line.is_empty());
+        let lists = WordLists {
+            stopwords: normalized(conf.stopwords_path.as_deref()).collect(),
+            blocklist: normalized(conf.blocklist_path.as_deref()).collect(),
+        };
+        tracing::info!("[WordLists] loaded. stopwords = {}, blocklist = {}", lists.stopwords.len(), lists.blocklist.len());
+        lists
+    })
+}
+
+fn blocked_term(text: &str) -> Option<&'static str> {
+    get_word_lists().blocklist.iter()
+        .find(|term| text.contains(term.as_str()))
+        .map(String::as_str)
+}
+
+/// Why a processed keyword is not worth a model call, `None` when it passes.
+pub fn reject_keyword(keyword: &str) -> Option<String> {
+    let conf = &config::get().event.prefilter;
+    let chars = keyword.chars().count();
+    if chars < conf.min_chars || chars > conf.max_chars {
+        return Some(format!("length {} out of [{}, {}]", chars, conf.min_chars, conf.max_chars));
+    }
+    if keyword.chars().any(char::is_control) {
+        return Some("control character".to_string());
+    }
+    if !keyword.chars().any(char::is_alphabetic) {
+        return Some("no letters".to_string());
+    }
+    let mut longest_run = 0;
+    let mut run = 0;
+    let mut prev = None;
+    for c in keyword.chars() {
+        run = if prev == Some(c) { run + 1 } else { 1 };
+        longest_run = usize::max(longest_run, run);
+        prev = Some(c);
+    }
+    if longest_run > conf.max_repeat {
+        return Some(format!("character repeated {} times", longest_run));
+    }
+    if get_word_lists().stopwords.contains(keyword) {
+        return Some("stopword".to_string());
+    }
+    blocked_term(keyword).map(|term| format!("blocked term {}", term))
+}
+
+/// Why a distilled event must not become a hotspot, `None` when it passes.
+pub fn reject_event(extracted: &ExtractedEvent) -> Option<String> {
+    if !extracted.is_meaningful {
+        return Some("judged meaningless".to_string());
+    }
+    let event = query::normalize(&extracted.event);
+    if event.is_empty() {
+        return Some("empty event".to_string());
+    }
+    blocked_term(&event).map(|term| format!("blocked term {}", term))
+}
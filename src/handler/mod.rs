@@ -1,4 +1,6 @@
 pub mod recommend_handler;
 pub mod search_handler;
+pub mod similar_handler;
+pub mod suggest_handler;
 pub mod embedding_handler;
 pub mod hotspot_handler;
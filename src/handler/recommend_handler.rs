@@ -1,33 +1,183 @@
-use crate::recommend::RecommendRequest;
-use crate::dal::{redis, milvus};
-use anyhow::{Context, Result};
+use std::collections::HashSet;
+use crate::recommend::{RecommendRequest, RecommendResponse, Scene};
+use crate::dal::{filter, redis, milvus};
+use crate::error::ServiceError;
+use crate::{config, moderation};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-pub async fn handle_recommend_request(req:RecommendRequest) -> Result<String> {
+const MAX_STEPS_PER_PAGE: i64 = 10;
+const MAX_SEEN: usize = 1000;
+
+/// Opaque pagination state handed back to the client as `next_cursor`.
+#[derive(Serialize, Deserialize, Default)]
+struct RecommendCursor {
+    step: i64,
+    seen: Vec<i64>,
+}
+
+impl RecommendCursor {
+    fn decode(cursor: &str) -> Result<Self> {
+        if cursor.is_empty() {
+            return Ok(Self::default());
+        }
+        // a cursor is only ever produced by `encode`, anything else is the caller's mistake
+        let bytes = URL_SAFE_NO_PAD.decode(cursor)
+            .map_err(|_| ServiceError::Param("cursor is not valid base64".to_string()))?;
+        let cursor = serde_json::from_slice(&bytes)
+            .map_err(|_| ServiceError::Param("cursor is malformed".to_string()))?;
+        Ok(cursor)
+    }
+
+    fn encode(&self) -> Result<String> {
+        let bytes = serde_json::to_vec(self)
+            .context("[RecommendCursor] serializing json err.")?;
+        Ok(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    fn mark_seen(&mut self, items: &[Value]) {
+        self.seen.extend(items.iter().filter_map(item_id));
+        if self.seen.len() > MAX_SEEN {
+            self.seen.drain(..self.seen.len() - MAX_SEEN);
+        }
+    }
+}
+
+fn item_id(item: &Value) -> Option<i64> {
+    item.get("item_id").and_then(Value::as_i64)
+}
+
+fn page_size(requested: i32) -> usize {
+    let conf = config::get();
+    let max = conf.validation.max_page_size.max(1) as usize;
+    match requested {
+        size if size <= 0 => conf.recommend.default_page_size.clamp(1, max),
+        size => (size as usize).min(max),
+    }
+}
+
+/// Drops the items the impression filter says the user was already shown.
+async fn drop_shown(user_id: i64, items: &mut Vec<Value>) -> Result<()> {
+    let ids: Vec<i64> = items.iter().filter_map(item_id).collect();
+    let exists = redis::execute_impression(user_id, &ids).await
+        .context("[drop_shown] execute_impression err.")?;
+    let shown: HashSet<i64> = ids.into_iter().zip(exists)
+        .filter_map(|(id, shown)| shown.then_some(id))
+        .collect();
+    items.retain(|item| item_id(item).is_none_or(|id| !shown.contains(&id)));
+    Ok(())
+}
+
+pub async fn handle_recommend_request(req:RecommendRequest) -> Result<RecommendResponse> {
+    let page_size = page_size(req.page_size);
+    let impression_dedup = config::get().recommend.impression_dedup;
+    let mut cursor = RecommendCursor::decode(&req.cursor)
+        .context("[handle_recommend_request] decode cursor err.")?;
+
+    if let Some(item_filter) = req.filter.as_ref() {
+        let fields = milvus::get_item_fields().await
+            .context("[handle_recommend_request] get_item_fields err.")?;
+        filter::validate_item_filter(item_filter, fields)
+            .context("[handle_recommend_request] invalid filter.")?;
+    }
+    let scene = Scene::try_from(req.scene).unwrap_or(Scene::Home);
+
+    // no history means a cold start, users have no registry to report NotFound from
     let item_history = redis::get_user_history(req.user_id).await
         .context("[handle_recommend_request] get_user_history err.")?;
-    let mut embeddings = milvus::get_item_embedding(item_history).await
-        .context("[handle_recommend_request] get_item_embedding err.")?;
+    let mut exclude_ids = req.exclude_ids.clone();
+    let mut embeddings = Vec::new();
+    match scene {
+        Scene::Detail | Scene::AfterPurchase => {
+            if req.item_id == 0 {
+                bail!(ServiceError::Param(format!("scene {:?} requires item_id", scene)));
+            }
+            // the seed item leads the recall with the largest leg, history fills the rest
+            let seed = milvus::get_item_embedding(vec![req.item_id]).await
+                .context("[handle_recommend_request] get seed item embedding err.")?;
+            if seed.is_empty() {
+                bail!(ServiceError::NotFound(format!("item {} not found", req.item_id)));
+            }
+            embeddings.extend(seed);
+            exclude_ids.push(req.item_id);
+            if scene == Scene::AfterPurchase {
+                exclude_ids.extend(item_history.iter().copied());
+            }
+        }
+        _ => {}
+    }
+    embeddings.extend(milvus::get_item_embedding(item_history).await
+        .context("[handle_recommend_request] get_item_embedding err.")?);
     while embeddings.len() < 6 {
         embeddings.push(milvus::random_embedding());
     }
-    let mut results = Vec::new();
-    let mut step =0;
-    while results.len()<20{
-        step+=1;
-        let mut new_results =milvus::recall_item(embeddings.clone(), step).await
+    let filter = filter::build_item_filter(req.filter.as_ref(), &exclude_ids);
+    let mut results: Vec<Value> = Vec::new();
+    let mut step = cursor.step;
+    let mut exhausted = false;
+    let max_step = milvus::max_recall_step();
+    while results.len() < page_size && step - cursor.step < MAX_STEPS_PER_PAGE {
+        // deeper steps would page past what milvus searches, the feed ends here
+        if step >= max_step {
+            exhausted = true;
+            break;
+        }
+        step += 1;
+        let mut new_results = milvus::recall_item(embeddings.clone(), step, &filter).await
             .context("[handle_recommend_request] recall_item err.")?;
-        new_results.retain(|item| !results.contains(item));
-        if new_results.len() == 0 {
+        if new_results.is_empty() {
+            exhausted = true;
             break;
         }
-        // new_results=redis::execute_impression(req.user_id,new_results).await
-        //     .context("[handle_recommend_request] execute_impression err.")?;
+        moderation::filter_items("recommend", &req.namespace, &mut new_results);
+        new_results.retain(|item| {
+            !results.contains(item) && item_id(item).is_none_or(|id| !cursor.seen.contains(&id))
+        });
+        if impression_dedup {
+            drop_shown(req.user_id, &mut new_results).await
+                .context("[handle_recommend_request] drop_shown err.")?;
+        }
         results.append(&mut new_results);
     }
-    results.truncate(20);
-    // redis::write_impression(req.user_id,results.clone()).await
-    //     .context("[handle_recommend_request] write_impression err.")?;
-    let result = serde_json::to_string(&results)
+    // replay the last step next time if part of it was cut off, the seen set filters what was shown
+    if results.len() > page_size {
+        results.truncate(page_size);
+        step -= 1;
+    }
+    if impression_dedup {
+        let shown: Vec<i64> = results.iter().filter_map(item_id).collect();
+        redis::write_impression(req.user_id, &shown).await
+            .context("[handle_recommend_request] write_impression err.")?;
+    }
+    let next_cursor = if exhausted {
+        String::new()
+    } else {
+        cursor.step = step;
+        cursor.mark_seen(&results);
+        cursor.encode()
+            .context("[handle_recommend_request] encode cursor err.")?
+    };
+    let results = serde_json::to_string(&results)
         .context("[handle_recommend_request] serialize json err.")?;
-    Ok(result)
-}
\ No newline at end of file
+    Ok(RecommendResponse {
+        results,
+        next_cursor,
+        base_resp: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_size_follows_the_config() {
+        assert_eq!(page_size(0), 20);
+        assert_eq!(page_size(-3), 20);
+        assert_eq!(page_size(7), 7);
+        assert_eq!(page_size(1000), 100);
+    }
+}
@@ -1,54 +1,333 @@
-use crate::dal::{kafka, milvus as milvus2, model};
-use crate::recommend::SearchRequest;
-use anyhow::{Context, Result};
-use milvus::value::ValueVec;
-use crate::hotspot;
-
-pub async fn handle_search_request(req: SearchRequest) -> Result<String> {
-    let embedding = model::call_multi_embedding_model(&vec![req.keyword.clone()], &vec![], &vec![]).await
-        .context("[handle_search_request] call_multi_embedding_model err.")?;
-    let results=milvus2::search_item(embedding, &req.keyword, req.page).await
-        .context("[handle_search_request] search_item err.")?;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::config::{self, Rerank, SearchProfile};
+use crate::dal::{filter, kafka, milvus as milvus2, model, redis};
+use crate::dal::search_log::{self, SearchLog, SearchResultLog};
+use crate::recommend::{Facet, RerankStrategy, SearchClickReport, SearchOptions, SearchRequest, SearchResponse, SortType};
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use time::OffsetDateTime;
+use crate::{event, hotspot, metrics, moderation, query};
+use crate::error::ServiceError;
+use crate::handler::suggest_handler;
+use crate::event::prefilter;
+
+const FACET_FIELD: &str = "category";
+
+pub async fn handle_search_request(req: SearchRequest) -> Result<SearchResponse> {
+    if req.page < 1 {
+        bail!(ServiceError::Param(format!("page must be >= 1, got {}", req.page)));
+    }
+    let sort = SortType::try_from(req.sort).unwrap_or(SortType::Relevance);
+    let profile = resolve_profile(&req.namespace, req.options.as_ref())
+        .context("[handle_search_request] invalid search options.")?;
+    let fields = milvus2::get_item_fields().await
+        .context("[handle_search_request] get_item_fields err.")?;
+    if let Some(item_filter) = req.filter.as_ref() {
+        filter::validate_item_filter(item_filter, fields)
+            .context("[handle_search_request] invalid filter.")?;
+    }
+    let mut extra_fields = Vec::new();
+    if let Some(sort_field) = sort_field(sort) {
+        filter::expect_field(fields, sort_field, filter::NUMERIC_TYPES)
+            .context("[handle_search_request] invalid sort.")?;
+        extra_fields.push(sort_field);
+    }
+    if req.with_facets {
+        filter::expect_field(fields, FACET_FIELD, filter::VARCHAR_TYPES)
+            .context("[handle_search_request] invalid facets.")?;
+        extra_fields.push(FACET_FIELD);
+    }
+    let filter = filter::build_item_filter(req.filter.as_ref(), &[]);
+    let processed = query::process(&req.keyword);
+
+    let window_key = window_key(&req, &processed, &filter, &extra_fields, &profile)
+        .context("[handle_search_request] window_key err.")?;
+    let window = match redis::get_search_window(&window_key).await {
+        Ok(cached) => {
+            metrics::observe_cache("search_window", if cached.is_some() { "hit" } else { "miss" });
+            cached
+        }
+        Err(e) => {
+            metrics::observe_cache("search_window", "error");
+            tracing::error!("[handle_search_request] get_search_window err. err = {:?}", e);
+            None
+        }
+    };
+    let mut window: Vec<Value> = match window {
+        Some(window) => serde_json::from_str(&window)
+            .context("[handle_search_request] cached window deserializing json err.")?,
+        None => {
+            // the llm rewrite only runs when the window is fetched, paging reuses its result
+            let rewritten = query::rewrite(&processed).await.unwrap_or_else(|e| {
+                tracing::error!("[handle_search_request] rewrite err. err = {:?}", e);
+                processed.clone()
+            });
+            let rewritten = query::expand(&rewritten);
+            tracing::info!("[handle_search_request] query processed. original = {}, rewritten = {}", req.keyword, rewritten);
+            let embedding = model::call_multi_embedding_model(&vec![rewritten.clone()], &vec![], &vec![]).await
+                .context("[handle_search_request] call_multi_embedding_model err.")?;
+            let mut window = milvus2::search_item(embedding, &rewritten, &filter, &extra_fields, &profile).await
+                .context("[handle_search_request] search_item err.")?;
+            if let Some(sort_field) = sort_field(sort) {
+                sort_items(&mut window, sort_field);
+            }
+            let cached = serde_json::to_string(&window)
+                .context("[handle_search_request] serialize json err.")?;
+            let ttl = config::get().search.window_cache_ttl_secs;
+            if let Err(e) = redis::set_search_window(&window_key, &cached, ttl).await {
+                tracing::error!("[handle_search_request] set_search_window err. err = {:?}", e);
+            }
+            window
+        }
+    };
+
+    // applied after the cache so blocklist changes take effect immediately
+    moderation::filter_items("search", &req.namespace, &mut window);
+    // facets, sort and total only cover the fused window, as documented on SearchResponse
+    let category_facets = if req.with_facets {
+        count_facets(&window, FACET_FIELD)
+    } else {
+        Vec::new()
+    };
+    let total = window.len();
+    let start = (req.page as usize - 1).saturating_mul(profile.page_size).min(total);
+    let end = start.saturating_add(profile.page_size).min(total);
+    let mut results = window[start..end].to_vec();
+    let request_id = format!("{:032x}", rand::random::<u128>());
+    let result_logs = results.iter()
+        .enumerate()
+        .filter_map(|(i, item)| item.get("item_id").and_then(Value::as_i64)
+            .map(|item_id| SearchResultLog { item_id, position: start + i + 1 }))
+        .collect();
+    let log = SearchLog::Search {
+        request_id: request_id.clone(),
+        namespace: req.namespace.clone(),
+        user_id: req.user_id,
+        query: req.keyword.clone(),
+        processed_query: processed.clone(),
+        page: req.page,
+        results: result_logs,
+        timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = search_log::write_search_log(&log).await {
+            tracing::error!("[handle_search_request] write_search_log err. err = {:?}", e);
+        }
+    });
+    for item in results.iter_mut().filter_map(Value::as_object_mut) {
+        for field in &extra_fields {
+            item.remove(*field);
+        }
+    }
     let result= serde_json::to_string(&results)
         .context("[handle_search_request] serialize json err.")?;
-    tokio::spawn(async move {
-        if let Err(e) = report_keyword(&(req.namespace+"_search"), &req.keyword).await {
-            tracing::error!("[handle_search_request] report_keywords err. err = {:?}", e);
+    // paging through the same query is not another search
+    if req.page == 1 {
+        tokio::spawn(async move {
+            if let Err(e) = suggest_handler::record_query(&req.namespace, &processed).await {
+                tracing::error!("[handle_search_request] record_query err. err = {:?}", e);
+            }
+            if let Err(e) = report_keyword(&(req.namespace+"_search"), &processed).await {
+                tracing::error!("[handle_search_request] report_keywords err. err = {:?}", e);
+            }
+        });
+    }
+    Ok(SearchResponse {
+        results: result,
+        category_facets,
+        total: total as i64,
+        has_more: end < total,
+        request_id,
+        base_resp: None,
+    })
+}
+
+pub async fn handle_search_click_report(report: SearchClickReport) -> Result<()> {
+    let log = SearchLog::Click {
+        request_id: report.request_id,
+        namespace: report.namespace,
+        user_id: report.user_id,
+        item_id: report.item_id,
+        position: report.position,
+        timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+    search_log::write_search_log(&log).await
+        .context("[handle_search_click_report] write_search_log err.")?;
+    Ok(())
+}
+
+/// Identifies a fused window by everything that shapes it, page and page size excluded.
+fn window_key(req: &SearchRequest, query: &str, filter: &str, extra_fields: &[&str], profile: &SearchProfile) -> Result<String> {
+    let mut window_profile = profile.clone();
+    window_profile.page_size = 0;
+    let profile = serde_json::to_string(&window_profile)
+        .context("[window_key] serialize json err.")?;
+    let identity = [query, filter, &extra_fields.join(","), &req.sort.to_string(), &profile].join("\u{1f}");
+    // FNV-1a, stable across processes unlike the std hasher
+    let hash = identity.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    Ok(format!("{}:{:016x}", req.namespace, hash))
+}
+
+/// Applies request overrides on top of the namespace profile, rejecting values outside the configured bounds.
+fn resolve_profile(namespace: &str, options: Option<&SearchOptions>) -> Result<SearchProfile> {
+    let search_conf = &config::get().search;
+    let bounds = &search_conf.bounds;
+    let mut profile = search_conf.profile(namespace).clone();
+    let Some(options) = options else {
+        profile.ef = profile.ef.max(profile.dense_limit);
+        return Ok(profile);
+    };
+
+    // switching strategy starts from its neutral parameters, keeping the same one keeps the profile's
+    match (RerankStrategy::try_from(options.rerank).unwrap_or(RerankStrategy::NotUse), &profile.rerank) {
+        (RerankStrategy::Rrf, Rerank::Weighted { .. }) => profile.rerank = Rerank::Rrf { k: 60 },
+        (RerankStrategy::Weighted, Rerank::Rrf { .. }) => profile.rerank = Rerank::Weighted { weights: vec![0.5, 0.5] },
+        _ => {}
+    }
+    if options.rrf_k != 0 {
+        let Rerank::Rrf { k } = &mut profile.rerank else {
+            bail!(ServiceError::Param("rrf_k requires the rrf rerank strategy".to_string()));
+        };
+        if options.rrf_k < 1 || options.rrf_k as u32 > bounds.max_rrf_k {
+            bail!(ServiceError::Param(format!("rrf_k must be in [1, {}]", bounds.max_rrf_k)));
         }
+        *k = options.rrf_k as u32;
+    }
+    if !options.weights.is_empty() {
+        let Rerank::Weighted { weights } = &mut profile.rerank else {
+            bail!(ServiceError::Param("weights require the weighted rerank strategy".to_string()));
+        };
+        if options.weights.len() != 2 || options.weights.iter().any(|w| !(0.0..=1.0).contains(w)) {
+            bail!(ServiceError::Param("weights must be two values in [0, 1], dense then sparse".to_string()));
+        }
+        *weights = options.weights.clone();
+    }
+    profile.dense_limit = bounded(options.dense_limit, profile.dense_limit, bounds.max_leg_limit, "dense_limit")?;
+    profile.sparse_limit = bounded(options.sparse_limit, profile.sparse_limit, bounds.max_leg_limit, "sparse_limit")?;
+    profile.page_size = bounded(options.page_size, profile.page_size, bounds.max_page_size, "page_size")?;
+    profile.ef = bounded(options.ef, profile.ef, bounds.max_ef, "ef")?;
+    if options.ef != 0 && profile.ef < profile.dense_limit {
+        bail!(ServiceError::Param(format!("ef must be at least dense_limit {}", profile.dense_limit)));
+    }
+    // milvus rejects an ef below the leg limit, a configured one is raised rather than failing every search
+    profile.ef = profile.ef.max(profile.dense_limit);
+    if options.drop_ratio_search != 0.0 {
+        if !(0.0..=bounds.max_drop_ratio_search).contains(&options.drop_ratio_search) {
+            bail!(ServiceError::Param(format!("drop_ratio_search must be in [0, {}]", bounds.max_drop_ratio_search)));
+        }
+        profile.drop_ratio_search = options.drop_ratio_search;
+    }
+    Ok(profile)
+}
+
+fn bounded(value: i32, default: usize, max: usize, name: &str) -> Result<usize> {
+    match value {
+        0 => Ok(default),
+        value if value < 0 || value as usize > max => bail!(ServiceError::Param(format!("{} must be in [1, {}]", name, max))),
+        value => Ok(value as usize),
+    }
+}
+
+fn sort_field(sort: SortType) -> Option<&'static str> {
+    match sort {
+        SortType::Newest => Some("create_time"),
+        SortType::Popularity => Some("popularity"),
+        _ => None,
+    }
+}
+
+/// Orders by the field descending, stable so relevance breaks ties.
+fn sort_items(items: &mut [Value], field: &str) {
+    items.sort_by(|a, b| {
+        let a = a.get(field).and_then(Value::as_f64).unwrap_or(f64::MIN);
+        let b = b.get(field).and_then(Value::as_f64).unwrap_or(f64::MIN);
+        b.partial_cmp(&a).unwrap_or(Ordering::Equal)
     });
-    Ok(result)
+}
+
+fn count_facets(items: &[Value], field: &str) -> Vec<Facet> {
+    let mut counts: HashMap<&str, i64> = HashMap::new();
+    for value in items.iter().filter_map(|item| item.get(field).and_then(Value::as_str)) {
+        *counts.entry(value).or_default() += 1;
+    }
+    let mut facets: Vec<Facet> = counts.into_iter()
+        .map(|(value, count)| Facet { value: value.to_string(), count })
+        .collect();
+    facets.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    facets
 }
 
 async fn report_keyword(namespace: &str, keyword: &str) -> Result<()> {
-    let event = model::call_event_model(keyword).await
+    if let Some(reason) = prefilter::reject_keyword(keyword) {
+        tracing::info!("[report_keyword] keyword dropped. keyword = {}, reason = {}", keyword, reason);
+        return Ok(());
+    }
+    let extracted = model::call_event_model(namespace, keyword).await
         .context("[report_keyword] call_event_model err.")?;
-    tracing::info!("[report_keyword] call_event_model. event = {:?}", event);
+    tracing::info!("[report_keyword] call_event_model. extracted = {:?}", extracted);
+    if let Some(reason) = prefilter::reject_event(&extracted) {
+        tracing::info!("[report_keyword] event dropped. keyword = {}, reason = {}", keyword, reason);
+        return Ok(());
+    }
+    let event = query::normalize(&extracted.event);
 
     let embedding = model::call_text_embedding_model(&event).await
         .context("[report_keyword] call_event_embedding_model err.")?;
 
-    if let Some(result) = milvus2::recall_event(embedding.clone()).await
-        .context("[report_keyword] recall_event err.")? {
-        let is_exist = result.score.first().copied().map_or(false, |score| score > 0.8);
-        if is_exist {
-            if let Some(ValueVec::String(event_name)) = result.field.first().map(|c| &c.value) {
-                if let Some(exist_event) = event_name.get(0).cloned() {
-                    // kafka::send_to_flink(namespace, &event).await
-                    //     .context("[report_keyword] send_to_flink err.")?;
-                    hotspot::detect_hotspot(namespace, &exist_event).await
-                        .context("[report_keyword] detect_hotspot err.")?;
-                    tracing::info!("[report_keyword] report exist event = {}", exist_event);
-                    return Ok(());
-                }
-            }
-        }
+    let canonical = event::resolve_event(namespace, &event, embedding).await
+        .context("[report_keyword] resolve_event err.")?;
+    if config::get().hotspot.flink_topk {
+        kafka::send_to_flink(namespace, &canonical).await
+            .context("[report_keyword] send_to_flink err.")?;
     }
-    milvus2::insert_event(&event, embedding).await
-        .context("[report_keyword] insert_event err.")?;
-    // kafka::send_to_flink(namespace, &event).await
-    //     .context("[report_keyword] send_to_flink err.")?;
-    hotspot::detect_hotspot(namespace, &event).await
+    hotspot::detect_hotspot(namespace, &canonical).await
         .context("[report_keyword] detect_hotspot err.")?;
-    tracing::info!("[report_keyword] report new event = {}", event);
+    tracing::info!("[report_keyword] report event = {}, category = {}, canonical = {}", event, extracted.category, canonical);
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> SearchOptions {
+        SearchOptions::default()
+    }
+
+    #[test]
+    fn default_profile_ef_covers_dense_limit() {
+        let profile = resolve_profile("default", None).unwrap();
+        assert!(profile.ef >= profile.dense_limit);
+        let profile = resolve_profile("default", Some(&options())).unwrap();
+        assert!(profile.ef >= profile.dense_limit);
+    }
+
+    #[test]
+    fn raised_dense_limit_raises_ef() {
+        let profile = resolve_profile("default", Some(&SearchOptions { dense_limit: 150, ..options() })).unwrap();
+        assert_eq!(profile.dense_limit, 150);
+        assert!(profile.ef >= 150);
+    }
+
+    #[test]
+    fn explicit_ef_below_dense_limit_is_rejected() {
+        let err = resolve_profile("default", Some(&SearchOptions { dense_limit: 100, ef: 10, ..options() })).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ServiceError>(), Some(ServiceError::Param(_))));
+    }
+
+    #[test]
+    fn window_key_ignores_page_and_page_size() {
+        let profile = resolve_profile("default", None).unwrap();
+        let req = SearchRequest { namespace: "shop".to_string(), keyword: "phone".to_string(), page: 1, ..Default::default() };
+        let key = window_key(&req, "phone", "", &[], &profile).unwrap();
+        let next_page = SearchRequest { page: 3, ..req.clone() };
+        let mut bigger_pages = profile.clone();
+        bigger_pages.page_size = 50;
+        assert_eq!(key, window_key(&next_page, "phone", "", &[], &bigger_pages).unwrap());
+        assert!(key.starts_with("shop:"));
+        assert_ne!(key, window_key(&req, "tablet", "", &[], &profile).unwrap());
+        assert_ne!(key, window_key(&req, "phone", "price < 10", &[], &profile).unwrap());
+    }
+}
@@ -0,0 +1,66 @@
+use crate::config;
+use crate::dal::{filter, milvus, redis};
+use crate::error::ServiceError;
+use crate::{metrics, moderation};
+use crate::recommend::SimilarItemsRequest;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+pub async fn handle_similar_items_request(req: SimilarItemsRequest) -> Result<String> {
+    let conf = &config::get().similar;
+    let limit = match req.limit {
+        limit if limit <= 0 => conf.default_limit,
+        limit => (limit as usize).min(conf.max_limit),
+    };
+    if let Some(item_filter) = req.filter.as_ref() {
+        let fields = milvus::get_item_fields().await
+            .context("[handle_similar_items_request] get_item_fields err.")?;
+        filter::validate_item_filter(item_filter, fields)
+            .context("[handle_similar_items_request] invalid filter.")?;
+    }
+    // only unfiltered results are shared between callers, cached at max_limit and sliced per request
+    let cacheable = conf.cache_ttl_secs > 0 && req.filter.is_none();
+    if cacheable {
+        match redis::get_similar_items(&req.namespace, req.item_id).await {
+            Ok(Some(cached)) => {
+                metrics::observe_cache("similar_items", "hit");
+                let mut items: Vec<Value> = serde_json::from_str(&cached)
+                    .context("[handle_similar_items_request] cached deserializing json err.")?;
+                moderation::filter_items("similar", &req.namespace, &mut items);
+                items.truncate(limit);
+                let result = serde_json::to_string(&items)
+                    .context("[handle_similar_items_request] serialize json err.")?;
+                return Ok(result);
+            }
+            Ok(None) => metrics::observe_cache("similar_items", "miss"),
+            Err(e) => {
+                metrics::observe_cache("similar_items", "error");
+                tracing::error!("[handle_similar_items_request] get_similar_items err. err = {:?}", e);
+            }
+        }
+    }
+
+    let embedding = milvus::get_item_embedding(vec![req.item_id]).await
+        .context("[handle_similar_items_request] get_item_embedding err.")?
+        .pop();
+    let Some(embedding) = embedding else {
+        bail!(ServiceError::NotFound(format!("item {} not found", req.item_id)));
+    };
+    let filter = filter::build_item_filter(req.filter.as_ref(), &[req.item_id]);
+    let search_limit = if cacheable { conf.max_limit } else { limit };
+    let mut items = milvus::search_similar_item(embedding, search_limit, conf.ef, &filter).await
+        .context("[handle_similar_items_request] search_similar_item err.")?;
+
+    if cacheable {
+        let cached = serde_json::to_string(&items)
+            .context("[handle_similar_items_request] serialize json err.")?;
+        if let Err(e) = redis::set_similar_items(&req.namespace, req.item_id, &cached, conf.cache_ttl_secs).await {
+            tracing::error!("[handle_similar_items_request] set_similar_items err. err = {:?}", e);
+        }
+    }
+    moderation::filter_items("similar", &req.namespace, &mut items);
+    items.truncate(limit);
+    let result = serde_json::to_string(&items)
+        .context("[handle_similar_items_request] serialize json err.")?;
+    Ok(result)
+}
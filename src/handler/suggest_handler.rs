@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use crate::config;
+use crate::dal::{milvus, redis};
+use crate::{moderation, query};
+use crate::recommend::{SuggestRequest, Suggestion};
+use anyhow::{bail, Context, Result};
+use time::OffsetDateTime;
+
+/// Fixed origin of the query weights, 2025-01-01 UTC. Weights double every half life after it.
+const WEIGHT_EPOCH: i64 = 1_735_689_600;
+/// Half lives per weight epoch. Each epoch scores into its own sorted sets with weights restarting
+/// at 1, so a weight stays below 2^32 however small the half life or late the date.
+const EPOCH_HALF_LIVES: f64 = 32.0;
+
+/// Trending topics first, then popular past queries, then item titles, each text once.
+/// A source that fails is skipped, the rpc only fails when none answered.
+pub async fn handle_suggest_request(req: SuggestRequest) -> Result<Vec<Suggestion>> {
+    let conf = &config::get().suggest;
+    let limit = match req.limit {
+        limit if limit <= 0 => conf.default_limit,
+        limit => (limit as usize).min(conf.max_limit),
+    };
+    // a prefix may end mid word, correcting it would rewrite what the user is still typing
+    let prefix = query::normalize(&req.prefix);
+    if prefix.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let hotspot_namespace = req.namespace.clone() + "_search";
+    let (epoch, _) = query_weight(OffsetDateTime::now_utc().unix_timestamp(), conf.half_life_hours);
+    let popular_queries = async {
+        let (current, previous) = redis::get_popular_queries(&req.namespace, &prefix, epoch, limit).await?;
+        Ok(merge_epochs(current, previous, limit))
+    };
+    let (hotspots, queries, titles) = tokio::join!(
+        redis::get_hotspots(&hotspot_namespace, &prefix, limit),
+        popular_queries,
+        milvus::query_item_titles(&prefix, limit),
+    );
+    let results = [("hotspot", hotspots), ("query", queries), ("item", titles)];
+    if results.iter().all(|(_, result)| result.is_err()) {
+        for (source, result) in results {
+            if let Err(e) = result {
+                tracing::error!("[handle_suggest_request] {} source err. err = {:?}", source, e);
+            }
+        }
+        bail!("[handle_suggest_request] every suggestion source failed.");
+    }
+
+    let mut suggestions: Vec<Suggestion> = Vec::with_capacity(limit);
+    for (source, result) in results {
+        let mut texts = match result {
+            Ok(texts) => texts,
+            Err(e) => {
+                tracing::error!("[handle_suggest_request] {} source err, skipped. err = {:?}", source, e);
+                continue;
+            }
+        };
+        moderation::filter_texts("suggest", &req.namespace, &mut texts);
+        for text in texts {
+            if suggestions.len() >= limit {
+                return Ok(suggestions);
+            }
+            if !suggestions.iter().any(|s| s.text == text) {
+                suggestions.push(Suggestion { text, source: source.to_string() });
+            }
+        }
+    }
+    Ok(suggestions)
+}
+
+/// Indexes a searched query for its prefixes. Later searches weigh more, so queries that
+/// stopped being searched sink below new ones instead of holding their place forever.
+pub async fn record_query(namespace: &str, query: &str) -> Result<()> {
+    let conf = &config::get().suggest;
+    let (epoch, weight) = query_weight(OffsetDateTime::now_utc().unix_timestamp(), conf.half_life_hours);
+    // an epoch's sets are read until the end of the next one
+    let ttl = (2.0 * EPOCH_HALF_LIVES * half_life_secs(conf.half_life_hours)) as i64;
+    let keep = conf.max_entries_per_prefix + conf.headroom_per_prefix;
+    redis::record_query(namespace, query, epoch, weight, conf.max_prefix_len, keep, ttl).await
+        .context("[record_query] redis record_query err.")?;
+    Ok(())
+}
+
+fn half_life_secs(half_life_hours: f64) -> f64 {
+    half_life_hours.max(0.01) * 3600.0
+}
+
+/// The weight epoch `now` falls in and the weight of a search at `now` within that epoch.
+fn query_weight(now: i64, half_life_hours: f64) -> (i64, f64) {
+    let half_lives = (now - WEIGHT_EPOCH) as f64 / half_life_secs(half_life_hours);
+    let epoch = (half_lives / EPOCH_HALF_LIVES).floor();
+    (epoch as i64, 2f64.powf(half_lives - epoch * EPOCH_HALF_LIVES))
+}
+
+/// Ranks the queries of the current and previous epoch together, scaling the previous
+/// epoch's scores down to the current epoch's weights.
+fn merge_epochs(current: Vec<(String, f64)>, previous: Vec<(String, f64)>, limit: usize) -> Vec<String> {
+    let scale = 2f64.powf(-EPOCH_HALF_LIVES);
+    let mut scores: HashMap<String, f64> = HashMap::new();
+    for (query, score) in current {
+        *scores.entry(query).or_default() += score;
+    }
+    for (query, score) in previous {
+        *scores.entry(query).or_default() += score * scale;
+    }
+    let mut ranked: Vec<(String, f64)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked.into_iter().take(limit).map(|(query, _)| query).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weight_doubles_every_half_life() {
+        let now = WEIGHT_EPOCH + 30 * 86400;
+        let (epoch, weight) = query_weight(now, 24.0);
+        let (next_epoch, next_weight) = query_weight(now + 86400, 24.0);
+        assert_eq!(epoch, next_epoch);
+        assert!((next_weight / weight - 2.0).abs() < 1e-9);
+        assert_eq!(query_weight(WEIGHT_EPOCH, 24.0), (0, 1.0));
+    }
+
+    #[test]
+    fn weight_stays_finite_past_the_old_overflow_point() {
+        // an unbounded 2^(elapsed / half life) overflowed after ~656 days at 15h and in ~2044 at 168h
+        for (half_life_hours, elapsed_days) in [(15.0, 656), (15.0, 10_000), (168.0, 7_000), (0.1, 3650)] {
+            let (epoch, weight) = query_weight(WEIGHT_EPOCH + elapsed_days * 86400, half_life_hours);
+            assert!(epoch > 0);
+            assert!(weight.is_finite() && (1.0..2f64.powi(32)).contains(&weight), "weight {}", weight);
+        }
+    }
+
+    #[test]
+    fn ranking_carries_over_an_epoch_boundary() {
+        let half_life_hours = 1.0;
+        let boundary = WEIGHT_EPOCH + 32 * 3600 * 100;
+        let (epoch, old) = query_weight(boundary - 3600, half_life_hours);
+        let (new_epoch, new) = query_weight(boundary, half_life_hours);
+        let (_, newer) = query_weight(boundary + 2 * 3600, half_life_hours);
+        assert_eq!(new_epoch, epoch + 1);
+        // three searches one half life before the boundary count as 1.5 fresh ones
+        let previous = vec![("old".to_string(), 3.0 * old)];
+        let current = vec![("new".to_string(), new), ("newer".to_string(), newer)];
+        assert_eq!(merge_epochs(current, previous, 3), vec!["newer", "old", "new"]);
+    }
+
+    #[test]
+    fn one_new_search_outweighs_a_stale_popular_query() {
+        let now = WEIGHT_EPOCH + 365 * 86400;
+        let (epoch, stale) = query_weight(now - 60 * 86400, 168.0);
+        let (new_epoch, new) = query_weight(now, 168.0);
+        assert_eq!(epoch, new_epoch);
+        assert!(new > 20.0 * stale);
+    }
+}
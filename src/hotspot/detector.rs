@@ -0,0 +1,306 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::config::DetectorConfig;
+
+/// Count-Min Sketch over string keys with fixed `width * depth` counters.
+struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    counters: Vec<u32>,
+}
+
+impl CountMinSketch {
+    fn new(width: usize, depth: usize) -> Self {
+        CountMinSketch {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    fn add(&mut self, key: &str) {
+        for row in 0..self.depth {
+            let index = self.index(row, key);
+            self.counters[index] = self.counters[index].saturating_add(1);
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u32 {
+        (0..self.depth)
+            .map(|row| self.counters[self.index(row, key)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn clear(&mut self) {
+        self.counters.fill(0);
+    }
+
+    fn index(&self, row: usize, key: &str) -> usize {
+        row * self.width + (hash(row as u64, key) % self.width as u64) as usize
+    }
+}
+
+/// Count-Min Sketch whose counts decay exponentially, used as the long-term traffic baseline.
+/// Uses forward decay: hits are weighted by `e^(lambda * (t - t0))` and scaled back on read.
+struct DecayedSketch {
+    width: usize,
+    depth: usize,
+    counters: Vec<f64>,
+    lambda: f64,
+    t0: i64,
+}
+
+impl DecayedSketch {
+    fn new(width: usize, depth: usize, half_life_secs: i64) -> Self {
+        DecayedSketch {
+            width,
+            depth,
+            counters: vec![0.0; width * depth],
+            lambda: std::f64::consts::LN_2 / half_life_secs.max(1) as f64,
+            t0: 0,
+        }
+    }
+
+    fn add(&mut self, key: &str, now: i64) {
+        let mut weight = (self.lambda * (now - self.t0) as f64).exp();
+        if !weight.is_finite() || weight > 1e12 {
+            let scale = 1.0 / weight;
+            self.counters.iter_mut().for_each(|c| *c *= scale);
+            self.t0 = now;
+            weight = 1.0;
+        }
+        for row in 0..self.depth {
+            let index = row * self.width + (hash(row as u64, key) % self.width as u64) as usize;
+            self.counters[index] += weight;
+        }
+    }
+
+    fn estimate(&self, key: &str, now: i64) -> f64 {
+        let min = (0..self.depth)
+            .map(|row| self.counters[row * self.width + (hash(row as u64, key) % self.width as u64) as usize])
+            .fold(f64::INFINITY, f64::min);
+        if min.is_finite() {
+            min * (-self.lambda * (now - self.t0) as f64).exp()
+        } else {
+            0.0
+        }
+    }
+
+    /// Decayed count to hits expected per `window_secs` at the long-term rate.
+    fn expected(&self, key: &str, now: i64, window_secs: i64) -> f64 {
+        self.estimate(key, now) * self.lambda * window_secs as f64
+    }
+}
+
+/// FNV-1a seeded per row, stable across processes so sketches stay comparable.
+fn hash(seed: u64, key: &str) -> u64 {
+    let basis = 0xcbf29ce484222325u64 ^ seed.wrapping_mul(0x9e3779b97f4a7c15);
+    key.bytes().fold(basis, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// What one hit tells about its key.
+pub struct Observation {
+    /// Hits within the window.
+    pub count: u32,
+    /// How far the window count is above the long-term baseline, in Poisson standard deviations.
+    pub score: f64,
+}
+
+/// Sliding window of per-bucket sketches plus the `top_k` keys seen most in the window.
+pub struct HotspotDetector {
+    conf: DetectorConfig,
+    buckets: Vec<CountMinSketch>,
+    history: DecayedSketch,
+    /// Absolute index (`ts / bucket_secs`) of the newest bucket.
+    current: i64,
+    heavy_hitters: HashMap<String, u32>,
+    /// `heavy_hitters` ordered by count, the first one is evicted for a hotter key.
+    by_count: BTreeSet<(u32, String)>,
+}
+
+impl HotspotDetector {
+    pub fn new(conf: DetectorConfig) -> Self {
+        let buckets = (0..conf.buckets.max(1))
+            .map(|_| CountMinSketch::new(conf.width.max(1), conf.depth.max(1)))
+            .collect();
+        let history = DecayedSketch::new(conf.width.max(1), conf.depth.max(1), conf.baseline_half_life_secs);
+        HotspotDetector {
+            conf,
+            buckets,
+            history,
+            current: 0,
+            heavy_hitters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    pub fn conf(&self) -> &DetectorConfig {
+        &self.conf
+    }
+
+    fn advance(&mut self, now: i64) {
+        let index = now / self.conf.bucket_secs();
+        if index <= self.current {
+            return;
+        }
+        let len = self.buckets.len() as i64;
+        let stale = (index - self.current).min(len);
+        for i in 0..stale {
+            self.buckets[((index - i).rem_euclid(len)) as usize].clear();
+        }
+        self.current = index;
+        self.refresh_heavy_hitters();
+    }
+
+    /// Re-reads the candidates' counts, which only drop when a bucket slides out of the window.
+    fn refresh_heavy_hitters(&mut self) {
+        let estimates: HashMap<String, u32> = self.heavy_hitters.keys()
+            .map(|k| (k.clone(), self.window_estimate(k)))
+            .collect();
+        self.by_count = estimates.iter().map(|(k, c)| (*c, k.clone())).collect();
+        self.heavy_hitters = estimates;
+    }
+
+    fn set_count(&mut self, key: &str, count: u32) {
+        if let Some(old) = self.heavy_hitters.insert(key.to_string(), count) {
+            self.by_count.remove(&(old, key.to_string()));
+        }
+        self.by_count.insert((count, key.to_string()));
+    }
+
+    fn window_estimate(&self, key: &str) -> u32 {
+        self.buckets.iter().map(|bucket| bucket.estimate(key)).sum()
+    }
+
+    /// Counts one hit and returns the key's window count and trend score when it is a heavy hitter.
+    /// `global_count` is the cluster-wide window count when counted elsewhere, the local sketch
+    /// then only ranks candidates and the local baseline is scaled by the global to local ratio.
+    pub fn record(&mut self, key: &str, now: i64, global_count: Option<u32>) -> Option<Observation> {
+        self.advance(now);
+        let len = self.buckets.len() as i64;
+        self.buckets[self.current.rem_euclid(len) as usize].add(key);
+        // the baseline is read before this hit joins it
+        let baseline = self.history.expected(key, now, self.conf.window_secs);
+        self.history.add(key, now);
+        let local_count = self.window_estimate(key);
+        let (count, baseline) = match global_count {
+            Some(global) => (global, baseline * global as f64 / local_count.max(1) as f64),
+            None => (local_count, baseline),
+        };
+        let observation = Observation {
+            count,
+            score: (count as f64 - baseline) / (baseline + 1.0).sqrt(),
+        };
+
+        if self.heavy_hitters.contains_key(key) || self.heavy_hitters.len() < self.conf.top_k {
+            self.set_count(key, local_count);
+            return Some(observation);
+        }
+        let (min_count, _) = self.by_count.first()?;
+        if local_count <= *min_count {
+            return None;
+        }
+        if let Some((_, min_key)) = self.by_count.pop_first() {
+            self.heavy_hitters.remove(&min_key);
+        }
+        self.set_count(key, local_count);
+        Some(observation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf() -> DetectorConfig {
+        DetectorConfig {
+            window_secs: 60,
+            buckets: 6,
+            width: 256,
+            depth: 4,
+            top_k: 2,
+            baseline_half_life_secs: 3600,
+            ..DetectorConfig::default()
+        }
+    }
+
+    #[test]
+    fn sketch_never_underestimates() {
+        let mut sketch = CountMinSketch::new(16, 3);
+        for i in 0..100 {
+            sketch.add(&format!("key{}", i % 20));
+        }
+        for i in 0..20 {
+            assert!(sketch.estimate(&format!("key{}", i)) >= 5);
+        }
+    }
+
+    #[test]
+    fn decayed_sketch_halves_per_half_life() {
+        let mut sketch = DecayedSketch::new(64, 4, 100);
+        sketch.add("a", 1_000);
+        assert!((sketch.estimate("a", 1_100) - 0.5).abs() < 1e-9);
+        assert!((sketch.estimate("a", 1_200) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn hits_slide_out_of_the_window() {
+        let mut detector = HotspotDetector::new(conf());
+        for _ in 0..5 {
+            detector.record("a", 1_000, None);
+        }
+        assert_eq!(detector.record("a", 1_030, None).unwrap().count, 6);
+        // the first five hits are older than window_secs by now
+        assert_eq!(detector.record("a", 1_070, None).unwrap().count, 2);
+    }
+
+    #[test]
+    fn burst_scores_above_steady_traffic() {
+        let mut detector = HotspotDetector::new(conf());
+        let mut now = 0;
+        // one hit a minute for a day builds the baseline
+        while now < 86_400 {
+            detector.record("steady", now, None);
+            now += 60;
+        }
+        let steady = detector.record("steady", now, None).unwrap().score;
+        let mut burst = 0.0;
+        for _ in 0..20 {
+            burst = detector.record("burst", now, None).unwrap().score;
+        }
+        assert!(steady < 2.0);
+        assert!(burst > 10.0);
+    }
+
+    #[test]
+    fn only_top_k_keys_are_candidates() {
+        let mut detector = HotspotDetector::new(conf());
+        for _ in 0..3 {
+            detector.record("a", 100, None);
+            detector.record("b", 100, None);
+        }
+        assert!(detector.record("c", 100, None).is_none());
+    }
+
+    #[test]
+    fn candidates_whose_hits_slid_out_are_evicted_first() {
+        let mut detector = HotspotDetector::new(conf());
+        for _ in 0..3 {
+            detector.record("a", 0, None);
+        }
+        detector.record("b", 50, None);
+        detector.record("b", 50, None);
+        // a's hits left the window, so c takes its place rather than b's
+        assert!(detector.record("c", 70, None).is_some());
+        assert!(detector.heavy_hitters.contains_key("b"));
+        assert!(!detector.heavy_hitters.contains_key("a"));
+        assert_eq!(detector.by_count.len(), detector.heavy_hitters.len());
+        assert!(detector.record("a", 70, None).is_none());
+    }
+
+    #[test]
+    fn global_count_replaces_the_local_count() {
+        let mut detector = HotspotDetector::new(conf());
+        assert_eq!(detector.record("a", 100, Some(42)).unwrap().count, 42);
+    }
+}
@@ -1,79 +1,139 @@
-use std::collections::{BinaryHeap, HashSet};
-use std::cmp::Reverse;
+mod detector;
+
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::cmp::Ordering;
 use anyhow::{Context, Result};
-use sentinel_core::{base, flow, EntryBuilder};
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use tokio::sync::{Mutex, OnceCell};
 use tokio::time::{interval, Duration};
+use crate::config::{self, HotspotCounting};
 use crate::dal::redis;
+use crate::{metrics, moderation};
+use detector::HotspotDetector;
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Hotspot {
-    key: String,
+    score: f64,
     timestamp: i64,
 }
-impl Ord for Hotspot {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.timestamp.cmp(&other.timestamp)
+impl Hotspot {
+    fn decayed_score(&self, now: i64, half_life_secs: i64) -> f64 {
+        self.score * 0.5f64.powf((now - self.timestamp) as f64 / half_life_secs.max(1) as f64)
     }
-}
-impl PartialOrd for Hotspot {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+
+    /// `log2` of the score decayed forward to the epoch instead of back to now. It orders hotspots like
+    /// their decayed scores at any time, so ranks written at different times compare directly.
+    fn rank(&self, half_life_secs: i64) -> f64 {
+        self.score.max(f64::MIN_POSITIVE).log2() + self.timestamp as f64 / half_life_secs.max(1) as f64
     }
 }
 
+/// What a replica saves every minute under its own field: only its hotspots, so the payload stays
+/// small however many keys the detector counts. The trend baseline is relearned after a restart.
+#[derive(Serialize, Deserialize)]
+struct HotspotSnapshot {
+    hotspots: HashMap<String, Hotspot>,
+    saved_at: i64,
+}
+
 struct HotspotManager {
-    set: HashSet<String>,
-    heap: BinaryHeap<Reverse<Hotspot>>,
+    hotspots: HashMap<String, Hotspot>,
+    detector: HotspotDetector,
 }
 impl HotspotManager {
-    fn new() -> Arc<Mutex<Self>> {
-        let manager = Arc::new(Mutex::new(HotspotManager {
-            set: HashSet::new(),
-            heap: BinaryHeap::new(),
-        }));
+    fn new(namespace: &str, hotspots: HashMap<String, Hotspot>) -> Arc<Mutex<Self>> {
+        let detector_conf = config::get().hotspot.detector(namespace).clone();
+        Self::start(namespace, HotspotManager {
+            hotspots,
+            detector: HotspotDetector::new(detector_conf),
+        })
+    }
+
+    /// Spawns the ticker that cools hotspots off, republishes the ranking and snapshots the state.
+    fn start(namespace: &str, manager: HotspotManager) -> Arc<Mutex<Self>> {
+        let manager = Arc::new(Mutex::new(manager));
         let manager_clone = manager.clone();
+        let namespace = namespace.to_string();
+        let hotspot_conf = &config::get().hotspot;
+        let replica = hotspot_conf.replica_id();
         tokio::spawn(async move {
             let mut ticker = interval(Duration::from_secs(60));
             loop {
                 ticker.tick().await;
                 let mut guard = manager_clone.lock().await;
-                guard.auto_remove();
+                let mut removed = guard.auto_remove();
+                removed.extend(guard.remove_blocked(&namespace));
+                let now = now_ts();
+                let ranked = guard.ranked(now);
+                let snapshot = HotspotSnapshot { hotspots: guard.hotspots.clone(), saved_at: now };
+                drop(guard);
+                if let Err(e) = redis::publish_hotspots(&namespace, &removed, &ranked).await {
+                    tracing::error!("[HotspotManager] publish_hotspots err. err = {:?}", e);
+                }
+                match serde_json::to_string(&snapshot) {
+                    Ok(snapshot) => {
+                        if let Err(e) = redis::save_hotspot_snapshot(&namespace, &replica, &snapshot, hotspot_conf.snapshot_ttl_secs).await {
+                            tracing::error!("[HotspotManager] save_hotspot_snapshot err. err = {:?}", e);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("[HotspotManager] snapshot serializing json err. err = {:?}", e);
+                    }
+                }
             }
         });
         manager
     }
 
-    fn insert(&mut self, hotspot: Hotspot) {
-        self.set.insert(hotspot.key.clone());
-        self.heap.push(Reverse(hotspot));
+    /// Raises the key's score to `score` if that beats its decayed score, returns whether it is new.
+    fn upsert(&mut self, key: &str, score: f64, now: i64) -> bool {
+        let half_life = self.detector.conf().score_half_life_secs;
+        match self.hotspots.get_mut(key) {
+            Some(hotspot) => {
+                if score > hotspot.decayed_score(now, half_life) {
+                    *hotspot = Hotspot { score, timestamp: now };
+                }
+                false
+            }
+            None => {
+                self.hotspots.insert(key.to_string(), Hotspot { score, timestamp: now });
+                true
+            }
+        }
+    }
+
+    /// Hotspots with their [`Hotspot::rank`] and trend score decayed to `now`, highest first.
+    fn ranked(&self, now: i64) -> Vec<(String, f64, f64)> {
+        let half_life = self.detector.conf().score_half_life_secs;
+        let mut ranked: Vec<(String, f64, f64)> = self.hotspots.iter()
+            .map(|(key, hotspot)| (key.clone(), hotspot.rank(half_life), hotspot.decayed_score(now, half_life)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
     }
 
-    fn contains(&self, hotspot: &Hotspot) -> bool {
-        self.set.contains(&hotspot.key)
+    fn remove_blocked(&mut self, namespace: &str) -> Vec<String> {
+        let blocked = moderation::blocked_hotspots(namespace, self.hotspots.keys());
+        for key in &blocked {
+            self.hotspots.remove(key);
+        }
+        blocked
     }
 
-    fn auto_remove(&mut self) {
+    fn auto_remove(&mut self) -> Vec<String> {
         let now = now_ts();
-        let expire_ts = now - 3600;
-        loop {
-            let need_remove = if let Some(Reverse(hotspot)) = self.heap.peek() {
-                hotspot.timestamp <= expire_ts
-            } else {
-                break;
-            };
-            if need_remove {
-                if let Some(Reverse(hotspot)) = self.heap.pop() {
-                    self.set.remove(&hotspot.key);
-                }
-            } else {
-                break;
-            }
+        let conf = self.detector.conf();
+        let (half_life, cooloff) = (conf.score_half_life_secs, conf.cooloff_score);
+        let removed: Vec<String> = self.hotspots.iter()
+            .filter(|(_, hotspot)| hotspot.decayed_score(now, half_life) < cooloff)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &removed {
+            self.hotspots.remove(key);
         }
+        removed
     }
 }
 
@@ -87,43 +147,184 @@ fn now_ts() -> i64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
 }
 
+/// Restores the hotspots of every namespace that has a snapshot, called once before traffic starts.
+pub async fn restore_hotspots() -> Result<()> {
+    let hotspot_conf = &config::get().hotspot;
+    let namespaces = redis::get_hotspot_namespaces().await
+        .context("[restore_hotspots] get_hotspot_namespaces err.")?;
+    let managers = get_hotspot_managers().await;
+    for namespace in namespaces {
+        let snapshots = redis::load_hotspot_snapshots(&namespace).await
+            .context("[restore_hotspots] load_hotspot_snapshots err.")?;
+        let mut parsed = Vec::with_capacity(snapshots.len());
+        for (snapshot_replica, snapshot) in snapshots {
+            match serde_json::from_str::<HotspotSnapshot>(&snapshot) {
+                Ok(snapshot) => parsed.push((snapshot_replica, snapshot)),
+                Err(e) => {
+                    tracing::error!("[restore_hotspots] snapshot deserializing json err. namespace = {}, replica = {}, err = {:?}", namespace, snapshot_replica, e);
+                }
+            }
+        }
+        let half_life = hotspot_conf.detector(&namespace).score_half_life_secs;
+        let (merged, stale) = merge_snapshots(parsed, now_ts() - hotspot_conf.snapshot_ttl_secs, half_life);
+        if let Err(e) = redis::remove_hotspot_snapshots(&namespace, &stale).await {
+            tracing::error!("[restore_hotspots] remove_hotspot_snapshots err. namespace = {}, err = {:?}", namespace, e);
+        }
+        if merged.is_empty() {
+            continue;
+        }
+        tracing::info!("[restore_hotspots] restored. namespace = {}, hotspots = {}", namespace, merged.len());
+        managers.insert(namespace.clone(), HotspotManager::new(&namespace, merged));
+    }
+    Ok(())
+}
+
+/// The hottest version of every hotspot a fresh snapshot knows. Snapshots saved before
+/// `stale_before` are skipped and returned so they can be removed.
+fn merge_snapshots(snapshots: Vec<(String, HotspotSnapshot)>, stale_before: i64, half_life_secs: i64) -> (HashMap<String, Hotspot>, Vec<String>) {
+    let mut merged: HashMap<String, Hotspot> = HashMap::new();
+    let mut stale = Vec::new();
+    for (replica, snapshot) in snapshots {
+        if snapshot.saved_at < stale_before {
+            stale.push(replica);
+            continue;
+        }
+        for (key, hotspot) in snapshot.hotspots {
+            match merged.get(&key) {
+                Some(known) if known.rank(half_life_secs) >= hotspot.rank(half_life_secs) => {}
+                _ => {
+                    merged.insert(key, hotspot);
+                }
+            }
+        }
+    }
+    (merged, stale)
+}
+
 pub async fn detect_hotspot(namespace: &str, key: &str) -> Result<()> {
-    let resource = format!("{}:{}", namespace, key);
-    flow::append_rule(Arc::new(flow::Rule {
-        id: resource.clone(),
-        resource: resource.clone(),
-        threshold: 2.0,
-        calculate_strategy: flow::CalculateStrategy::Direct,
-        control_strategy: flow::ControlStrategy::Reject,
-        stat_interval_ms: 600000,
-        ..Default::default()
-    }));
-
-    let entry_builder = EntryBuilder::new(resource)
-        .with_traffic_type(base::TrafficType::Inbound);
-
-    if let Ok(entry) = entry_builder.build() {
-        entry.exit();
-    } else {
-        let manager = get_hotspot_managers().await
-            .entry(namespace.to_string())
-            .or_insert_with(|| {
-                HotspotManager::new()
-            })
-            .clone();
-        let mut guard = manager.lock().await;
-
-        let hotspot = Hotspot {
-            key: key.to_string(),
-            timestamp: now_ts(),
-        };
-        if !guard.contains(&hotspot) {
-            guard.insert(hotspot);
-            //TODO:push by grpc streaming
-            redis::set_hotspot(namespace, key).await
-                .context("[detect_hotspot] redis set err.")?;
-            tracing::info!("[detect_hotspot] new hotspot. namespace = {}, key = {}", namespace, key);
+    let now = now_ts();
+    let detector_conf = config::get().hotspot.detector(namespace);
+    let global_count = match detector_conf.counting {
+        HotspotCounting::Local => None,
+        HotspotCounting::Redis => {
+            match redis::incr_hotspot_count(namespace, key, now, detector_conf.bucket_secs(), detector_conf.buckets).await {
+                Ok(count) => Some(count),
+                Err(e) => {
+                    tracing::error!("[detect_hotspot] incr_hotspot_count err, using local count. err = {:?}", e);
+                    None
+                }
+            }
         }
+    };
+
+    let manager = get_hotspot_managers().await
+        .entry(namespace.to_string())
+        .or_insert_with(|| {
+            HotspotManager::new(namespace, HashMap::new())
+        })
+        .clone();
+    let mut guard = manager.lock().await;
+
+    let Some(observation) = guard.detector.record(key, now, global_count) else {
+        return Ok(());
+    };
+    let conf = guard.detector.conf();
+    if observation.count <= conf.threshold || observation.score < conf.min_trend_score {
+        return Ok(());
+    }
+    if !guard.hotspots.contains_key(key) {
+        // moderation may ask the chat model, other keys of the namespace must not wait for it
+        drop(guard);
+        if !moderation::allow_hotspot(namespace, key).await
+            .context("[detect_hotspot] allow_hotspot err.")? {
+            return Ok(());
+        }
+        guard = manager.lock().await;
+    }
+    let is_new = guard.upsert(key, observation.score, now);
+    let half_life = guard.detector.conf().score_half_life_secs;
+    let hotspot = &guard.hotspots[key];
+    let published = (key.to_string(), hotspot.rank(half_life), hotspot.decayed_score(now, half_life));
+    drop(guard);
+    redis::publish_hotspots(namespace, &[], &[published]).await
+        .context("[detect_hotspot] publish_hotspots err.")?;
+    // another replica may have announced it already
+    let announce_ttl = config::get().hotspot.announce_ttl_secs;
+    if is_new && redis::claim_hotspot(namespace, key, announce_ttl).await
+        .context("[detect_hotspot] claim_hotspot err.")? {
+        //TODO:push by grpc streaming
+        metrics::observe_hotspot(namespace);
+        tracing::info!("[detect_hotspot] new hotspot. namespace = {}, key = {}, count = {}, score = {:.2}",
+            namespace, key, observation.count, observation.score);
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_orders_like_the_decayed_score() {
+        let half_life = 1800;
+        let old = Hotspot { score: 40.0, timestamp: 1_000_000 };
+        let new = Hotspot { score: 15.0, timestamp: 1_000_000 + 2 * half_life };
+        let now = 1_000_000 + 3 * half_life;
+        // 40 decayed over two half lives is 10, below the newer 15
+        assert!(old.decayed_score(now, half_life) < new.decayed_score(now, half_life));
+        assert!(old.rank(half_life) < new.rank(half_life));
+    }
+
+    #[test]
+    fn rank_is_independent_of_when_it_is_read() {
+        let half_life = 600;
+        let hotspot = Hotspot { score: 8.0, timestamp: 5_000 };
+        let later = Hotspot { score: hotspot.decayed_score(5_000 + half_life, half_life), timestamp: 5_000 + half_life };
+        assert!((hotspot.rank(half_life) - later.rank(half_life)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ranked_exposes_the_decayed_trend_score() {
+        let mut manager = HotspotManager {
+            hotspots: HashMap::new(),
+            detector: HotspotDetector::new(config::DetectorConfig::default()),
+        };
+        let half_life = manager.detector.conf().score_half_life_secs;
+        manager.upsert("hot", 8.0, 1_000);
+        manager.upsert("warm", 6.0, 1_000 + half_life);
+        let ranked = manager.ranked(1_000 + half_life);
+        assert_eq!(ranked[0].0, "warm");
+        assert!((ranked[0].2 - 6.0).abs() < 1e-9);
+        assert_eq!(ranked[1].0, "hot");
+        assert!((ranked[1].2 - 4.0).abs() < 1e-9);
+    }
+
+    fn snapshot(saved_at: i64, hotspots: &[(&str, f64)]) -> HotspotSnapshot {
+        HotspotSnapshot {
+            hotspots: hotspots.iter()
+                .map(|(key, score)| (key.to_string(), Hotspot { score: *score, timestamp: saved_at }))
+                .collect(),
+            saved_at,
+        }
+    }
+
+    #[test]
+    fn merge_keeps_the_hottest_fresh_hotspots() {
+        let snapshots = vec![
+            ("a".to_string(), snapshot(100, &[("x", 5.0)])),
+            ("b".to_string(), snapshot(200, &[("x", 9.0), ("y", 3.0)])),
+            ("c".to_string(), snapshot(10, &[("z", 50.0)])),
+        ];
+        let (merged, stale) = merge_snapshots(snapshots, 50, 1800);
+        assert_eq!(merged["x"].score, 9.0);
+        assert!(merged.contains_key("y"));
+        assert!(!merged.contains_key("z"));
+        assert_eq!(stale, vec!["c".to_string()]);
+    }
+
+    #[test]
+    fn snapshot_leaves_the_sketches_out() {
+        let json = serde_json::to_string(&snapshot(100, &[("x", 5.0)])).unwrap();
+        assert!(json.len() < 100, "snapshot is {} bytes", json.len());
+    }
+}
@@ -0,0 +1,242 @@
+use std::future::Future;
+use std::sync::{LazyLock, OnceLock};
+use anyhow::{Context, Result};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, sleep_until, timeout_at, Duration, Instant};
+use tonic_health::ServingStatus;
+use tonic_health::server::HealthReporter;
+use crate::dal::{kafka, redis};
+use crate::{config, event};
+
+/// Flips to `true` once, when shutdown starts.
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::Sender::new(false));
+/// `drain_timeout_secs` after shutdown started, the one budget every drain phase shares.
+static DRAIN_DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+/// Starts the shutdown, waking everything waiting in [`shutdown_started`].
+pub fn begin_shutdown() {
+    DRAIN_DEADLINE.get_or_init(|| Instant::now() + Duration::from_secs(config::get().lifecycle.drain_timeout_secs));
+    SHUTDOWN.send_if_modified(|down| !std::mem::replace(down, true));
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+pub async fn shutdown_started() {
+    let mut down = SHUTDOWN.subscribe();
+    let _ = down.wait_for(|down| *down).await;
+}
+
+/// Resolves `drain_timeout_secs` after shutdown started.
+pub async fn drain_deadline() {
+    shutdown_started().await;
+    sleep_until(deadline()).await;
+}
+
+fn deadline() -> Instant {
+    *DRAIN_DEADLINE.get_or_init(Instant::now)
+}
+
+/// Resolves `shutdown_grace_secs` after shutdown started. The health service reports not serving
+/// from the start, the grace gives load balancers time to notice before the listener closes.
+pub async fn listener_closing() {
+    shutdown_started().await;
+    let grace = Duration::from_secs(config::get().lifecycle.shutdown_grace_secs);
+    sleep_until((Instant::now() + grace).min(deadline())).await;
+    tracing::info!("[listener_closing] grace period over, closing the listener");
+}
+
+/// Waits for SIGTERM or ctrl-c, then starts the shutdown.
+pub async fn wait_for_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("[wait_for_signal] install SIGTERM handler err. err = {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = terminate => tracing::info!("[wait_for_signal] SIGTERM received, shutting down"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("[wait_for_signal] ctrl-c received, shutting down"),
+    }
+    begin_shutdown();
+}
+
+/// Delay between retries, doubled from `initial_backoff_ms` per failure up to `max_backoff_ms`.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        let conf = &config::get().lifecycle;
+        let initial = Duration::from_millis(conf.initial_backoff_ms);
+        Backoff { initial, max: Duration::from_millis(conf.max_backoff_ms), next: initial }
+    }
+
+    /// Starts over from `initial_backoff_ms`, after a success.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+
+    /// The delay to wait now, doubling the one after it.
+    fn advance(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Sleeps the next delay, returning false instead once shutdown starts.
+    pub async fn wait(&mut self) -> bool {
+        tokio::select! {
+            _ = sleep(self.advance()) => !is_shutting_down(),
+            _ = shutdown_started() => false,
+        }
+    }
+}
+
+/// Runs `connect` until it succeeds, doubling the backoff between failures. Gives up after
+/// `attempts` tries, or with 0 once shutdown starts.
+pub async fn retry<T, F, Fut>(what: &str, attempts: u32, mut connect: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = Backoff::new();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let e = match connect().await {
+            Ok(connected) => return Ok(connected),
+            Err(e) => e,
+        };
+        if attempt == attempts || is_shutting_down() {
+            return Err(e).with_context(|| format!("[retry] {} failed after {} attempts.", what, attempt));
+        }
+        tracing::warn!("[retry] {} failed, retrying in {:?}. attempt = {}, err = {:?}", what, backoff.next, attempt, e);
+        backoff.wait().await;
+    }
+}
+
+/// Builds the DAL clients before serving. A dependency still down after `connect_attempts`
+/// is logged and left to reconnect on use, the rest keeps serving.
+pub async fn connect_dependencies() {
+    let attempts = config::get().lifecycle.connect_attempts;
+    if let Err(e) = retry("milvus", attempts, event::check_collections).await {
+        tracing::error!("[connect_dependencies] milvus unavailable. err = {:?}", e);
+    }
+    if let Err(e) = retry("redis", attempts, redis::ping).await {
+        tracing::error!("[connect_dependencies] redis unavailable. err = {:?}", e);
+    }
+    if let Err(e) = kafka::get_flink_producer().await {
+        tracing::error!("[connect_dependencies] kafka unavailable. err = {:?}", e);
+    }
+}
+
+async fn check_dependencies() -> Result<()> {
+    event::check_collections().await
+        .context("[check_dependencies] check_collections err.")?;
+    redis::ping().await
+        .context("[check_dependencies] redis ping err.")?;
+    Ok(())
+}
+
+/// Reports `service` serving while its dependencies are healthy, and not serving from shutdown on.
+pub async fn start_readiness_checker(reporter: HealthReporter, service: &'static str) {
+    let mut ticker = interval(Duration::from_secs(config::get().lifecycle.readiness_interval_secs.max(1)));
+    let mut last = ServingStatus::Unknown;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown_started() => break,
+        }
+        let checked = tokio::select! {
+            checked = check_dependencies() => checked,
+            _ = shutdown_started() => break,
+        };
+        let status = match checked {
+            Ok(()) => ServingStatus::Serving,
+            Err(e) => {
+                tracing::error!("[start_readiness_checker] dependency check err. err = {:?}", e);
+                ServingStatus::NotServing
+            }
+        };
+        if status != last {
+            tracing::info!("[start_readiness_checker] {} is now {:?}", service, status);
+            reporter.set_service_status(service, status).await;
+            reporter.set_service_status("", status).await;
+            last = status;
+        }
+    }
+    reporter.set_service_status(service, ServingStatus::NotServing).await;
+    reporter.set_service_status("", ServingStatus::NotServing).await;
+}
+
+/// Lets the consumers finish their current batch, then writes the buffered events, within
+/// what is left of the drain deadline after the rpcs drained.
+pub async fn drain(consumers: Vec<JoinHandle<()>>) {
+    begin_shutdown();
+    let drained = async {
+        for consumer in consumers {
+            if let Err(e) = consumer.await {
+                tracing::error!("[drain] consumer task err. err = {:?}", e);
+            }
+        }
+        if let Err(e) = event::flush_events().await {
+            tracing::error!("[drain] flush_events err. err = {:?}", e);
+        }
+    };
+    match timeout_at(deadline(), drained).await {
+        Ok(()) => tracing::info!("[drain] drained"),
+        Err(_) => tracing::warn!("[drain] drain timed out, pending work is dropped"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_and_resets() {
+        let mut backoff = Backoff { initial: Duration::from_millis(100), max: Duration::from_millis(350), next: Duration::from_millis(100) };
+        let delays: Vec<u128> = (0..4).map(|_| backoff.advance().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 350, 350]);
+        backoff.reset();
+        assert_eq!(backoff.advance(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_stops_at_the_first_success() {
+        let mut calls = 0;
+        let connected = retry("test", 5, || {
+            calls += 1;
+            let call = calls;
+            async move { if call < 3 { Err(anyhow!("down")) } else { Ok(call) } }
+        }).await.unwrap();
+        assert_eq!(connected, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_gives_up_after_its_attempts() {
+        let start = Instant::now();
+        let mut calls = 0;
+        let result: Result<()> = retry("test", 3, || {
+            calls += 1;
+            async { Err(anyhow!("down")) }
+        }).await;
+        assert_eq!(calls, 3);
+        assert!(result.unwrap_err().to_string().contains("failed after 3 attempts"));
+        // two waits at the default 500ms then 1000ms
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+}
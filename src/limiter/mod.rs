@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::future::{ready, Ready};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use http_body_util::{BodyExt, Full};
+use moka::sync::Cache;
+use prost::Message;
+use sentinel_core::{base, flow, EntryBuilder};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::body::Body;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{http, BoxFuture, Bytes};
+use tonic::metadata::MetadataMap;
+use tonic::server::{Grpc, UnaryService};
+use tonic::{Request, Response, Status};
+use tower::{Layer, Service};
+use crate::common::BaseResp;
+use crate::config::{self, RpcLimit, RpcLimits};
+use crate::error::{self, ServiceError};
+use crate::metrics;
+
+/// Limited rpcs, each namespace gets one sentinel rule and one semaphore per rpc.
+const RPCS: [&str; 2] = ["recommend", "search"];
+/// Shared by the namespaces not known at config load, only reachable while validation accepts any namespace.
+const OTHER_NAMESPACE: &str = "other";
+
+/// In-flight permits per `{rpc}:{namespace}`, built once by [`init`].
+static CONCURRENCY: LazyLock<HashMap<String, Arc<Semaphore>>> = LazyLock::new(|| {
+    let mut semaphores = HashMap::new();
+    for namespace in limited_namespaces() {
+        let limits = limits(&namespace);
+        for rpc in RPCS {
+            let limit = rpc_limit(limits, rpc);
+            if limit.max_concurrency > 0 {
+                semaphores.insert(format!("{}:{}", rpc, namespace), Arc::new(Semaphore::new(limit.max_concurrency)));
+            }
+        }
+    }
+    semaphores
+});
+
+/// Per caller token buckets, the idle and least used ones are evicted past `max_callers`.
+static CALLER_BUCKETS: LazyLock<Cache<String, Arc<Mutex<TokenBucket>>>> = LazyLock::new(|| {
+    let conf = &config::get().rate_limit;
+    Cache::builder()
+        .max_capacity(conf.max_callers)
+        .time_to_idle(Duration::from_secs(conf.caller_idle_secs.max(1)))
+        .build()
+});
+
+/// Namespaces with their own limits: the registered ones and those with configured limits.
+fn limited_namespaces() -> HashSet<String> {
+    let conf = config::get();
+    conf.validation.namespaces.iter()
+        .chain(conf.rate_limit.namespaces.keys())
+        .cloned()
+        .chain(std::iter::once(OTHER_NAMESPACE.to_string()))
+        .collect()
+}
+
+/// The namespace whose limits apply, [`OTHER_NAMESPACE`] for one not known at config load.
+fn limited_namespace(namespace: &str) -> &str {
+    if config::get().knows_namespace(namespace) { namespace } else { OTHER_NAMESPACE }
+}
+
+fn limits(namespace: &str) -> &'static RpcLimits {
+    config::get().rate_limit.limits(namespace)
+}
+
+fn rpc_limit<'a>(limits: &'a RpcLimits, rpc: &str) -> &'a RpcLimit {
+    match rpc {
+        "search" => &limits.search,
+        _ => &limits.recommend,
+    }
+}
+
+fn qps_resource(rpc: &str, namespace: &str) -> String {
+    format!("{}:ns:{}", rpc, namespace)
+}
+
+/// Loads the namespace QPS rules into sentinel and builds the semaphores, once after sentinel is initialized.
+pub fn init() {
+    let mut rules = Vec::new();
+    for namespace in limited_namespaces() {
+        let limits = limits(&namespace);
+        for rpc in RPCS {
+            let qps = rpc_limit(limits, rpc).namespace_qps;
+            if qps <= 0.0 {
+                continue;
+            }
+            let resource = qps_resource(rpc, &namespace);
+            rules.push(Arc::new(flow::Rule {
+                id: resource.clone(),
+                resource,
+                threshold: qps,
+                calculate_strategy: flow::CalculateStrategy::Direct,
+                control_strategy: flow::ControlStrategy::Reject,
+                stat_interval_ms: 1000,
+                ..Default::default()
+            }));
+        }
+    }
+    tracing::info!("[init] loading {} namespace qps rules", rules.len());
+    flow::load_rules(rules);
+    LazyLock::force(&CONCURRENCY);
+}
+
+/// The caller id a request may claim: its `x-caller-id` when `authorization` carries that
+/// caller's configured bearer token.
+fn authenticated_caller(metadata: &MetadataMap, tokens: &HashMap<String, String>) -> Option<String> {
+    let caller = metadata.get("x-caller-id")?.to_str().ok()?;
+    let token = metadata.get("authorization")?.to_str().ok()?.strip_prefix("Bearer ")?;
+    let expected = tokens.get(caller)?;
+    // compares every byte, so a guess learns nothing from timing
+    let matches = token.len() == expected.len()
+        && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    matches.then(|| caller.to_string())
+}
+
+/// Refills at `qps` tokens a second up to a one second burst.
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(qps: f64, now: Instant) -> Self {
+        TokenBucket { tokens: qps, refilled_at: now }
+    }
+
+    fn take(&mut self, qps: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * qps).min(qps);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+fn pass_namespace_qps(rpc: &str, namespace: &str) -> bool {
+    match EntryBuilder::new(qps_resource(rpc, namespace)).with_traffic_type(base::TrafficType::Inbound).build() {
+        Ok(entry) => {
+            entry.exit();
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn pass_caller_qps(rpc: &str, namespace: &str, caller: Option<&str>, qps: f64) -> bool {
+    // an unauthenticated caller may be a gateway speaking for many users, only the namespace limits apply
+    let Some(caller) = caller else {
+        return true;
+    };
+    if qps <= 0.0 {
+        return true;
+    }
+    let now = Instant::now();
+    let bucket = CALLER_BUCKETS.get_with(format!("{}:{}:{}", rpc, namespace, caller),
+        || Arc::new(Mutex::new(TokenBucket::new(qps, now))));
+    bucket.lock().unwrap_or_else(|e| e.into_inner()).take(qps, now)
+}
+
+/// Admits one `rpc` call, the returned permit holds its concurrency slot until dropped.
+fn acquire(rpc: &str, namespace: &str, caller: Option<&str>) -> Result<Option<OwnedSemaphorePermit>, ServiceError> {
+    let namespace = limited_namespace(namespace);
+    let limit = rpc_limit(limits(namespace), rpc);
+
+    let permit = match CONCURRENCY.get(&format!("{}:{}", rpc, namespace)) {
+        Some(semaphore) => Some(semaphore.clone().try_acquire_owned()
+            .map_err(|_| ServiceError::OverFrequency(format!("too many concurrent {} requests in namespace {}", rpc, namespace)))?),
+        None => None,
+    };
+    if limit.namespace_qps > 0.0 && !pass_namespace_qps(rpc, namespace) {
+        return Err(ServiceError::OverFrequency(format!("{} qps limit of namespace {} exceeded", rpc, namespace)));
+    }
+    if !pass_caller_qps(rpc, namespace, caller, limit.caller_qps) {
+        return Err(ServiceError::OverFrequency(format!("{} qps limit of caller {} exceeded", rpc, caller.unwrap_or_default())));
+    }
+    Ok(permit)
+}
+
+/// The limited rpc a grpc path calls.
+fn limited_rpc(path: &str) -> Option<&'static str> {
+    match path {
+        "/recommend.RecommendService/Recommend" => Some("recommend"),
+        "/recommend.RecommendService/Search" => Some("search"),
+        _ => None,
+    }
+}
+
+/// The `namespace` every request message carries as field 1.
+#[derive(Clone, PartialEq, Message)]
+struct Namespaced {
+    #[prost(string, tag = "1")]
+    namespace: String,
+}
+
+/// Any response message with only its `baseResp`, which all of them carry as field 255.
+#[derive(Clone, PartialEq, Message)]
+struct Rejected {
+    #[prost(message, optional, tag = "255")]
+    base_resp: Option<BaseResp>,
+}
+
+/// The namespace of a buffered unary grpc request, empty when the frame is compressed or malformed.
+fn request_namespace(body: &[u8]) -> String {
+    // a frame is a compression flag, the big endian message length and the message
+    let Some(([0, len @ ..], message)) = body.split_first_chunk::<5>() else {
+        return String::new();
+    };
+    message.get(..u32::from_be_bytes(*len) as usize)
+        .and_then(|message| Namespaced::decode(message).ok())
+        .map(|request| request.namespace)
+        .unwrap_or_default()
+}
+
+/// Answers the request with the rejection's `BaseResp` and an ok grpc status, like a handler would.
+struct Reject(BaseResp);
+
+impl UnaryService<Namespaced> for Reject {
+    type Response = Rejected;
+    type Future = Ready<Result<Response<Rejected>, Status>>;
+
+    fn call(&mut self, _: Request<Namespaced>) -> Self::Future {
+        ready(Ok(Response::new(Rejected { base_resp: Some(self.0.clone()) })))
+    }
+}
+
+/// Tower layer admitting every limited rpc through the namespace and caller limits before it
+/// reaches the service. A caller is told apart only by its authenticated `x-caller-id`.
+#[derive(Clone, Default)]
+pub struct RateLimitLayer;
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> RateLimit<S> {
+        RateLimit { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+}
+
+impl<S> Service<http::Request<Body>> for RateLimit<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        // the clone that was polled ready serves this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let Some(rpc) = limited_rpc(request.uri().path()) else {
+                return inner.call(request).await;
+            };
+            let start = Instant::now();
+            let (parts, body) = request.into_parts();
+            let body = match body.collect().await {
+                Ok(collected) => collected.to_bytes(),
+                Err(status) => return Ok(status.into_http()),
+            };
+            let namespace = request_namespace(&body);
+            let metadata = MetadataMap::from_headers(parts.headers.clone());
+            let caller = authenticated_caller(&metadata, &config::get().rate_limit.caller_tokens);
+            let request = http::Request::from_parts(parts, Body::new(Full::<Bytes>::new(body)));
+            match acquire(rpc, &namespace, caller.as_deref()) {
+                Ok(_permit) => inner.call(request).await,
+                Err(rejection) => {
+                    let base_resp = error::error_resp(rpc, rejection.into());
+                    metrics::observe_rpc(rpc, &namespace, "OverFrequency_Error", start);
+                    let mut grpc = Grpc::new(ProstCodec::<Rejected, Namespaced>::default());
+                    Ok(grpc.unary(Reject(base_resp), request).await)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert!(bucket.take(2.0, start));
+        assert!(bucket.take(2.0, start));
+        assert!(!bucket.take(2.0, start));
+        assert!(bucket.take(2.0, start + Duration::from_millis(500)));
+        assert!(!bucket.take(2.0, start + Duration::from_millis(500)));
+        // idle time refills no more than one second's worth
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(2.0, later));
+        assert!(bucket.take(2.0, later));
+        assert!(!bucket.take(2.0, later));
+    }
+
+    fn metadata(pairs: &[(&'static str, &'static str)]) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        for (key, value) in pairs {
+            metadata.insert(*key, value.parse().unwrap());
+        }
+        metadata
+    }
+
+    #[test]
+    fn caller_id_needs_its_token() {
+        let tokens = HashMap::from([("shop".to_string(), "secret".to_string())]);
+        let trusted = metadata(&[("x-caller-id", "shop"), ("authorization", "Bearer secret")]);
+        assert_eq!(authenticated_caller(&trusted, &tokens).as_deref(), Some("shop"));
+        let wrong_token = metadata(&[("x-caller-id", "shop"), ("authorization", "Bearer secreT")]);
+        assert_eq!(authenticated_caller(&wrong_token, &tokens), None);
+        let no_token = metadata(&[("x-caller-id", "shop")]);
+        assert_eq!(authenticated_caller(&no_token, &tokens), None);
+        let unknown = metadata(&[("x-caller-id", "other"), ("authorization", "Bearer secret")]);
+        assert_eq!(authenticated_caller(&unknown, &tokens), None);
+    }
+
+    #[test]
+    fn unauthenticated_callers_skip_the_caller_buckets() {
+        for _ in 0..10 {
+            assert!(pass_caller_qps("search", "shop", None, 1.0));
+        }
+        assert!(pass_caller_qps("search", "shop", Some("gateway"), 1.0));
+        assert!(!pass_caller_qps("search", "shop", Some("gateway"), 1.0));
+    }
+
+    fn frame(message: &impl Message) -> Vec<u8> {
+        let encoded = message.encode_to_vec();
+        let mut frame = vec![0];
+        frame.extend((encoded.len() as u32).to_be_bytes());
+        frame.extend(encoded);
+        frame
+    }
+
+    #[test]
+    fn namespace_is_read_from_any_request() {
+        let search = crate::recommend::SearchRequest { namespace: "shop".to_string(), keyword: "phone".to_string(), ..Default::default() };
+        assert_eq!(request_namespace(&frame(&search)), "shop");
+        let recommend = crate::recommend::RecommendRequest { namespace: "video".to_string(), user_id: 7, ..Default::default() };
+        assert_eq!(request_namespace(&frame(&recommend)), "video");
+        assert_eq!(request_namespace(&[1, 0, 0, 0, 0]), "");
+        assert_eq!(request_namespace(&[0, 0, 0, 0, 9, 1]), "");
+    }
+
+    #[tokio::test]
+    async fn rejection_decodes_as_the_rpc_response() {
+        let search = crate::recommend::SearchRequest { namespace: "shop".to_string(), ..Default::default() };
+        let request = http::Request::builder()
+            .uri("/recommend.RecommendService/Search")
+            .header("content-type", "application/grpc")
+            .body(Body::new(Full::<Bytes>::new(frame(&search).into())))
+            .unwrap();
+        let base_resp = error::error_resp("search", ServiceError::OverFrequency("limited".to_string()).into());
+        let mut grpc = Grpc::new(ProstCodec::<Rejected, Namespaced>::default());
+        let response = grpc.unary(Reject(base_resp), request).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let decoded = crate::recommend::SearchResponse::decode(&body[5..]).unwrap();
+        let base_resp = decoded.base_resp.unwrap();
+        assert_eq!(base_resp.status_code, crate::common::StatusCode::OverFrequencyError as i32);
+        assert_eq!(base_resp.status_message, "limited");
+    }
+
+    #[test]
+    fn only_recommend_and_search_are_limited() {
+        assert_eq!(limited_rpc("/recommend.RecommendService/Search"), Some("search"));
+        assert_eq!(limited_rpc("/recommend.RecommendService/Suggest"), None);
+        assert_eq!(limited_rpc("/grpc.health.v1.Health/Check"), None);
+    }
+
+    #[test]
+    fn unknown_namespaces_share_the_other_limits() {
+        assert_eq!(limited_namespace("never_configured"), OTHER_NAMESPACE);
+    }
+}
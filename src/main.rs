@@ -1,7 +1,17 @@
+mod config;
 mod consumer;
 mod dal;
+mod error;
+mod event;
 mod handler;
 mod hotspot;
+mod lifecycle;
+mod limiter;
+mod metrics;
+mod moderation;
+mod query;
+mod telemetry;
+mod validation;
 
 pub mod common {
     tonic::include_proto!("common");
@@ -10,88 +20,211 @@ pub mod recommend {
     tonic::include_proto!("recommend");
 }
 
+use std::time::Instant;
 use common::{BaseResp, StatusCode};
 use recommend::recommend_service_server::{RecommendService, RecommendServiceServer};
 use recommend::{RecommendRequest, RecommendResponse};
 use recommend::{SearchRequest, SearchResponse};
+use recommend::{SimilarItemsRequest, SimilarItemsResponse};
+use recommend::{SuggestRequest, SuggestResponse};
+use recommend::{SearchClickReport, SearchClickResponse};
 use tonic::{transport::Server, Request, Response, Status};
+use tonic::server::NamedService;
+use tracing::Instrument;
 use handler::recommend_handler::handle_recommend_request;
-use handler::search_handler::handle_search_request;
+use handler::search_handler::{handle_search_click_report, handle_search_request};
+use handler::similar_handler::handle_similar_items_request;
+use handler::suggest_handler::handle_suggest_request;
+use validation::Validate;
 
 #[derive(Debug, Default)]
 pub struct MyRecommendService {}
 
+/// Every rpc response carries a `BaseResp`.
+trait WithBaseResp: Default {
+    fn set_base_resp(&mut self, base_resp: BaseResp);
+}
+
+macro_rules! impl_with_base_resp {
+    ( $($t: ty),+ ) => {$(
+        impl WithBaseResp for $t {
+            fn set_base_resp(&mut self, base_resp: BaseResp) {
+                self.base_resp = Some(base_resp);
+            }
+        }
+    )*};
+}
+
+impl_with_base_resp! {
+    RecommendResponse,
+    SearchResponse,
+    SimilarItemsResponse,
+    SuggestResponse,
+    SearchClickResponse
+}
+
+/// Fills in the `BaseResp` of a handler result and records the rpc's latency and status.
+#[allow(clippy::result_large_err)]
+fn reply<T: WithBaseResp>(method: &str, namespace: &str, start: Instant, result: anyhow::Result<T>) -> Result<Response<T>, Status> {
+    let (mut response, base_resp) = match result {
+        Ok(response) => (response, error::success()),
+        Err(e) => {
+            tracing::Span::current().record("rpc.grpc.status_code", error::grpc_code(&e) as i32);
+            (T::default(), error::error_resp(method, e))
+        }
+    };
+    let status = StatusCode::try_from(base_resp.status_code).map_or("unknown", |code| code.as_str_name());
+    metrics::observe_rpc(method, namespace, status, start);
+    response.set_base_resp(base_resp);
+    Ok(Response::new(response))
+}
+
 #[tonic::async_trait]
 impl RecommendService for MyRecommendService {
     async fn recommend(
         &self,
         request: Request<RecommendRequest>,
     ) -> Result<Response<RecommendResponse>, Status> {
+        let start = Instant::now();
+        let span = telemetry::rpc_span("recommend", &request.get_ref().namespace, request.metadata());
         let req = request.into_inner();
-        match handle_recommend_request(req).await{
-            Ok(results) => {
-                let base_resp = BaseResp {
-                    status_code: StatusCode::Success as i32,
-                    status_message: "Success".to_string(),
-                };
-                let response = RecommendResponse {
-                    results,
-                    base_resp: Some(base_resp),
-                };
-                Ok(Response::new(response))
-            }
-            Err(e) => {
-                tracing::error!("[recommend] handle_recommend_request err. err = {}", e);
-                Err(Status::internal(format!("Error: {}", e)))
-            }
-        }
+        let namespace = req.namespace.clone();
+        let result = async {
+            req.validate()?;
+            handle_recommend_request(req).await
+        }.instrument(span.clone()).await;
+        let _entered = span.enter();
+        reply("recommend", &namespace, start, result)
     }
 
     async fn search(
         &self,
         request: Request<SearchRequest>,
     ) -> Result<Response<SearchResponse>, Status> {
+        let start = Instant::now();
+        let span = telemetry::rpc_span("search", &request.get_ref().namespace, request.metadata());
         let req = request.into_inner();
-        match handle_search_request(req).await{
-            Ok(results) => {
-                let base_resp = BaseResp {
-                    status_code: StatusCode::Success as i32,
-                    status_message: "Success".to_string(),
-                };
-                let response = SearchResponse {
-                    results,
-                    base_resp: Some(base_resp),
-                };
-                Ok(Response::new(response))
-            }
-            Err(e) => {
-                tracing::error!("[search] handle_search_request err. err = {}", e);
-                Err(Status::internal(format!("Error: {}", e)))
-            }
-        }
+        let namespace = req.namespace.clone();
+        let result = async {
+            req.validate()?;
+            handle_search_request(req).await
+        }.instrument(span.clone()).await;
+        let _entered = span.enter();
+        reply("search", &namespace, start, result)
+    }
+
+    async fn similar_items(
+        &self,
+        request: Request<SimilarItemsRequest>,
+    ) -> Result<Response<SimilarItemsResponse>, Status> {
+        let start = Instant::now();
+        let span = telemetry::rpc_span("similar_items", &request.get_ref().namespace, request.metadata());
+        let req = request.into_inner();
+        let namespace = req.namespace.clone();
+        let result = async {
+            req.validate()?;
+            let results = handle_similar_items_request(req).await?;
+            Ok(SimilarItemsResponse { results, ..Default::default() })
+        }.instrument(span.clone()).await;
+        let _entered = span.enter();
+        reply("similar_items", &namespace, start, result)
+    }
+
+    async fn suggest(
+        &self,
+        request: Request<SuggestRequest>,
+    ) -> Result<Response<SuggestResponse>, Status> {
+        let start = Instant::now();
+        let span = telemetry::rpc_span("suggest", &request.get_ref().namespace, request.metadata());
+        let req = request.into_inner();
+        let namespace = req.namespace.clone();
+        let result = async {
+            req.validate()?;
+            let suggestions = handle_suggest_request(req).await?;
+            Ok(SuggestResponse { suggestions, ..Default::default() })
+        }.instrument(span.clone()).await;
+        let _entered = span.enter();
+        reply("suggest", &namespace, start, result)
+    }
+
+    async fn report_search_click(
+        &self,
+        request: Request<SearchClickReport>,
+    ) -> Result<Response<SearchClickResponse>, Status> {
+        let start = Instant::now();
+        let span = telemetry::rpc_span("report_search_click", &request.get_ref().namespace, request.metadata());
+        let req = request.into_inner();
+        let namespace = req.namespace.clone();
+        let result = async {
+            req.validate()?;
+            handle_search_click_report(req).await?;
+            Ok(SearchClickResponse::default())
+        }.instrument(span.clone()).await;
+        let _entered = span.enter();
+        reply("report_search_click", &namespace, start, result)
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
-
+    let conf = config::load()?;
+    telemetry::init()?;
+    // connecting may retry for a while, a SIGTERM meanwhile must still stop the process
+    tokio::spawn(lifecycle::wait_for_signal());
     sentinel_core::init_default().expect("Failed to initialize Sentinel");
+    limiter::init();
+    lifecycle::connect_dependencies().await;
+    if lifecycle::is_shutting_down() {
+        tracing::info!("[main] shut down while connecting dependencies");
+        telemetry::shutdown();
+        return Ok(());
+    }
+    if let Err(e) = hotspot::restore_hotspots().await {
+        tracing::error!("[main] restore_hotspots err. err = {:?}", e);
+    }
 
-    tokio::spawn(async move {
-        consumer::start_rocketmq().await;
-    });
-    // tokio::spawn(async move {
-    //     consumer::start_kafka().await;
-    // });
+    let mut consumers = vec![tokio::spawn(consumer::start_rocketmq())];
+    if conf.hotspot.flink_topk {
+        consumers.push(tokio::spawn(consumer::start_kafka()));
+    }
+    tokio::spawn(event::start_event_merger());
+    tokio::spawn(event::start_event_flusher());
+    tokio::spawn(event::start_event_compactor());
+    tokio::spawn(moderation::start_moderation_reloader());
+
+    if let Some(metrics_addr) = &config::get().telemetry.metrics_addr {
+        let metrics_addr = metrics_addr.parse()
+            .map_err(|e| format!("[main] parse metrics_addr {} err. err = {}", metrics_addr, e))?;
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(metrics_addr).await {
+                tracing::error!("[main] serve_metrics err. err = {:?}", e);
+            }
+        });
+    }
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    let service_name = RecommendServiceServer::<MyRecommendService>::NAME;
+    health_reporter.set_service_status(service_name, tonic_health::ServingStatus::NotServing).await;
+    health_reporter.set_service_status("", tonic_health::ServingStatus::NotServing).await;
+    tokio::spawn(lifecycle::start_readiness_checker(health_reporter, service_name));
 
     let addr = "127.0.0.1:3006".parse()?;
     let recommend_service = MyRecommendService::default();
-    Server::builder()
+    let server = Server::builder()
+        .layer(limiter::RateLimitLayer)
+        .add_service(health_service)
         .add_service(RecommendServiceServer::new(recommend_service))
-        .serve(addr)
-        .await?;
+        .serve_with_shutdown(addr, lifecycle::listener_closing());
+    // in-flight rpcs finish inside serve_with_shutdown, bounded by the drain deadline
+    let served = tokio::select! {
+        served = server => served,
+        _ = lifecycle::drain_deadline() => {
+            tracing::warn!("[main] in-flight rpcs did not finish before the drain timeout");
+            Ok(())
+        }
+    };
+    lifecycle::drain(consumers).await;
+    telemetry::shutdown();
+    served?;
     Ok(())
 }
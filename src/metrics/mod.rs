@@ -0,0 +1,142 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Instant;
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::http::header::CONTENT_TYPE;
+use axum::routing::get;
+use prometheus::{register_gauge_vec, register_histogram_vec, register_int_counter_vec};
+use prometheus::{Encoder, GaugeVec, HistogramVec, IntCounterVec, TextEncoder};
+use tracing::Instrument;
+use crate::{config, validation};
+
+static RPC_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "rpc_duration_seconds", "RPC latency by method, namespace and BaseResp status.",
+    &["method", "namespace", "status"]
+).expect("Failed to register rpc_duration_seconds"));
+
+static DAL_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "dal_duration_seconds", "Latency of calls to Milvus, Redis, Kafka and the model APIs.",
+    &["backend", "operation"]
+).expect("Failed to register dal_duration_seconds"));
+
+static DAL_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "dal_errors_total", "Failed calls to Milvus, Redis, Kafka and the model APIs.",
+    &["backend", "operation"]
+).expect("Failed to register dal_errors_total"));
+
+static CONSUMER_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "consumer_messages_total", "Consumed messages by source and outcome.",
+    &["source", "result"]
+).expect("Failed to register consumer_messages_total"));
+
+static CONSUMER_LAG: LazyLock<GaugeVec> = LazyLock::new(|| register_gauge_vec!(
+    "consumer_lag_seconds", "Age of the last consumed message when it was received.",
+    &["source"]
+).expect("Failed to register consumer_lag_seconds"));
+
+static HOTSPOTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "hotspots_detected_total", "Keys that newly became hotspots.",
+    &["namespace"]
+).expect("Failed to register hotspots_detected_total"));
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "cache_lookups_total", "Cache lookups by cache and result: hit, miss or error.",
+    &["cache", "result"]
+).expect("Failed to register cache_lookups_total"));
+
+/// Keeps the namespace label bounded: `invalid` for a namespace validation rejects by its form,
+/// `other` for a well-formed one the config does not know.
+fn namespace_label(namespace: &str) -> &str {
+    if !validation::well_formed_namespace(namespace) {
+        "invalid"
+    } else if config::get().knows_namespace(namespace) {
+        namespace
+    } else {
+        "other"
+    }
+}
+
+/// [`namespace_label`] for hotspot namespaces, which are a request namespace plus a source suffix
+/// like `_search` or have a detector of their own.
+fn hotspot_namespace_label(namespace: &str) -> &str {
+    let conf = config::get();
+    if !validation::well_formed_namespace(namespace) {
+        "invalid"
+    } else if conf.hotspot.detectors.contains_key(namespace)
+        || namespace.rsplit_once('_').is_some_and(|(base, _)| conf.knows_namespace(base)) {
+        namespace
+    } else {
+        "other"
+    }
+}
+
+pub fn observe_rpc(method: &str, namespace: &str, status: &str, start: Instant) {
+    RPC_DURATION.with_label_values(&[method, namespace_label(namespace), status]).observe(start.elapsed().as_secs_f64());
+}
+
+/// Runs one DAL call in its own span, recording its latency and whether it failed.
+pub async fn observe_dal<T>(backend: &str, operation: &str, call: impl Future<Output = Result<T>>) -> Result<T> {
+    let span = tracing::info_span!("dal", otel.name = format!("{} {}", backend, operation), otel.kind = "client", backend, operation);
+    let start = Instant::now();
+    let result = call.instrument(span).await;
+    DAL_DURATION.with_label_values(&[backend, operation]).observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        DAL_ERRORS.with_label_values(&[backend, operation]).inc();
+    }
+    result
+}
+
+/// Counts one consumed message, `born_ms` is when the producer created it.
+pub fn observe_message(source: &str, born_ms: Option<i64>, ok: bool) {
+    CONSUMER_MESSAGES.with_label_values(&[source, if ok { "ok" } else { "error" }]).inc();
+    if let Some(born_ms) = born_ms {
+        let now_ms = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+        let lag = (now_ms as i64 - born_ms).max(0) as f64 / 1000.0;
+        CONSUMER_LAG.with_label_values(&[source]).set(lag);
+    }
+}
+
+pub fn observe_hotspot(namespace: &str) {
+    HOTSPOTS.with_label_values(&[hotspot_namespace_label(namespace)]).inc();
+}
+
+/// `result` is `hit`, `miss` or `error`.
+pub fn observe_cache(cache: &str, result: &str) {
+    CACHE_LOOKUPS.with_label_values(&[cache, result]).inc();
+}
+
+async fn render() -> ([(axum::http::HeaderName, String); 1], Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("[render] encode metrics err. err = {:?}", e);
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], buffer)
+}
+
+/// Serves `GET /metrics` in the Prometheus text format.
+pub async fn serve_metrics(addr: SocketAddr) -> Result<()> {
+    let app = Router::new().route("/metrics", get(render));
+    let listener = tokio::net::TcpListener::bind(addr).await
+        .with_context(|| format!("[serve_metrics] bind {} err.", addr))?;
+    tracing::info!("[serve_metrics] listening on {}", addr);
+    axum::serve(listener, app).await
+        .context("[serve_metrics] serve err.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_namespaces_do_not_become_labels() {
+        assert_eq!(namespace_label(""), "invalid");
+        assert_eq!(namespace_label("shop\n{evil}"), "invalid");
+        assert_eq!(namespace_label("never_registered"), "other");
+        assert_eq!(hotspot_namespace_label("never_registered_search"), "other");
+        assert_eq!(hotspot_namespace_label("a b_search"), "invalid");
+    }
+}
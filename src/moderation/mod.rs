@@ -0,0 +1,186 @@
+use std::collections::HashSet;
+use std::fs;
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use moka::sync::Cache;
+use regex::Regex;
+use serde_json::Value;
+use tokio::time::{interval, Duration};
+use crate::config;
+use crate::dal::{model, redis};
+use crate::dal::moderation_log::{self, ModerationLog};
+use crate::query;
+
+/// Keyword and regex lists with the modification times they were read at.
+#[derive(Default)]
+struct Rules {
+    keywords: Vec<String>,
+    patterns: Vec<Regex>,
+    versions: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl Rules {
+    fn load() -> Self {
+        let conf = &config::get().moderation;
+        let keywords: Vec<String> = query::read_lines(conf.keywords_path.as_deref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|line| query::normalize(&line))
+            .filter(|line| !line.is_empty())
+            .collect();
+        let patterns: Vec<Regex> = query::read_lines(conf.patterns_path.as_deref())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|line| match Regex::new(&line) {
+                Ok(pattern) => Some(pattern),
+                Err(e) => {
+                    tracing::error!("[Rules] invalid pattern skipped. pattern = {}, err = {}", line, e);
+                    None
+                }
+            })
+            .collect();
+        tracing::info!("[Rules] loaded. keywords = {}, patterns = {}", keywords.len(), patterns.len());
+        Rules { keywords, patterns, versions: Self::versions() }
+    }
+
+    fn versions() -> (Option<SystemTime>, Option<SystemTime>) {
+        let conf = &config::get().moderation;
+        let modified = |path: Option<&str>| path
+            .and_then(|path| fs::metadata(path).ok())
+            .and_then(|meta| meta.modified().ok());
+        (modified(conf.keywords_path.as_deref()), modified(conf.patterns_path.as_deref()))
+    }
+
+    fn check(&self, text: &str) -> Option<String> {
+        let normalized = query::normalize(text);
+        if let Some(keyword) = self.keywords.iter().find(|k| normalized.contains(k.as_str())) {
+            return Some(format!("keyword {}", keyword));
+        }
+        self.patterns.iter()
+            .find(|p| p.is_match(text) || p.is_match(&normalized))
+            .map(|p| format!("pattern {}", p.as_str()))
+    }
+}
+
+static RULES: LazyLock<RwLock<Arc<Rules>>> = LazyLock::new(|| RwLock::new(Arc::new(Rules::load())));
+static BLOCKED_ITEMS: LazyLock<RwLock<Arc<HashSet<i64>>>> = LazyLock::new(Default::default);
+/// Chat model verdicts per hotspot key, `Some(reason)` when blocked. Cleared when the lists change.
+static LLM_VERDICTS: LazyLock<Cache<String, Option<String>>> = LazyLock::new(|| {
+    let conf = &config::get().moderation;
+    Cache::builder()
+        .max_capacity(conf.llm_verdict_capacity)
+        .time_to_live(Duration::from_secs(conf.llm_verdict_ttl_secs))
+        .build()
+});
+
+fn rules() -> Arc<Rules> {
+    RULES.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn blocked_items() -> Arc<HashSet<i64>> {
+    BLOCKED_ITEMS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn audit(surface: &str, namespace: &str, subject: &str, reason: &str) {
+    tracing::info!("[moderation] suppressed. surface = {}, namespace = {}, subject = {}, reason = {}", surface, namespace, subject, reason);
+    let log = ModerationLog::new(surface, namespace, subject, reason);
+    tokio::spawn(async move {
+        if let Err(e) = moderation_log::write_moderation_log(&log).await {
+            tracing::error!("[moderation] write_moderation_log err. err = {:?}", e);
+        }
+    });
+}
+
+/// Why `text` is blocked by the keyword and regex lists, `None` when it passes.
+pub fn check_text(text: &str) -> Option<String> {
+    rules().check(text)
+}
+
+/// Lists first, then the chat model when enabled, audited when blocked.
+pub async fn allow_hotspot(namespace: &str, key: &str) -> Result<bool> {
+    if let Some(reason) = check_text(key) {
+        audit("hotspot", namespace, key, &reason);
+        return Ok(false);
+    }
+    if !config::get().moderation.llm_hotspots {
+        return Ok(true);
+    }
+    let verdict = match LLM_VERDICTS.get(key) {
+        Some(verdict) => verdict,
+        None => {
+            let verdict = model::call_moderation_model(key).await
+                .context("[allow_hotspot] call_moderation_model err.")?;
+            let verdict = (!verdict.allowed).then(|| format!("llm {}", verdict.reason));
+            LLM_VERDICTS.insert(key.to_string(), verdict.clone());
+            verdict
+        }
+    };
+    match verdict {
+        Some(reason) => {
+            audit("hotspot", namespace, key, &reason);
+            Ok(false)
+        }
+        None => Ok(true),
+    }
+}
+
+/// Drops blocked hotspot keys already ranked, e.g. after the lists changed.
+pub fn blocked_hotspots<'a>(namespace: &str, keys: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let rules = rules();
+    keys.filter_map(|key| {
+            let reason = rules.check(key)
+                .or_else(|| LLM_VERDICTS.get(key).flatten())?;
+            audit("hotspot", namespace, key, &reason);
+            Some(key.clone())
+        })
+        .collect()
+}
+
+/// Keeps the texts that pass the lists.
+pub fn filter_texts(surface: &str, namespace: &str, texts: &mut Vec<String>) {
+    let rules = rules();
+    texts.retain(|text| match rules.check(text) {
+        Some(reason) => {
+            audit(surface, namespace, text, &reason);
+            false
+        }
+        None => true,
+    });
+}
+
+/// Removes blocked item ids from an item list.
+pub fn filter_items(surface: &str, namespace: &str, items: &mut Vec<Value>) {
+    let blocked = blocked_items();
+    if blocked.is_empty() {
+        return;
+    }
+    items.retain(|item| match item.get("item_id").and_then(Value::as_i64) {
+        Some(id) if blocked.contains(&id) => {
+            audit(surface, namespace, &id.to_string(), "blocked item");
+            false
+        }
+        _ => true,
+    });
+}
+
+/// Reloads the lists when their files change and refreshes the item blocklist from redis.
+pub async fn start_moderation_reloader() -> ! {
+    let mut ticker = interval(Duration::from_secs(config::get().moderation.reload_interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        if rules().versions != Rules::versions() {
+            let reloaded = Arc::new(Rules::load());
+            *RULES.write().unwrap_or_else(|e| e.into_inner()) = reloaded;
+            LLM_VERDICTS.invalidate_all();
+        }
+        match redis::get_blocked_items().await {
+            Ok(items) => {
+                *BLOCKED_ITEMS.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(items);
+            }
+            Err(e) => {
+                tracing::error!("[start_moderation_reloader] get_blocked_items err. err = {:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+use anyhow::{Context, Result};
+use crate::config;
+use crate::dal::model;
+use crate::event::prefilter;
+
+static DICTIONARIES: OnceLock<Dictionaries> = OnceLock::new();
+
+#[derive(Default)]
+struct Dictionaries {
+    t2s: HashMap<char, char>,
+    /// Sorted longest first so longer misspellings win over their substrings.
+    corrections: Vec<(String, String)>,
+    synonyms: Vec<Vec<String>>,
+}
+
+impl Dictionaries {
+    fn load() -> Self {
+        let conf = &config::get().query;
+        let mut dictionaries = Dictionaries::default();
+        if let Some(lines) = read_lines(conf.t2s_path.as_deref()) {
+            for line in lines {
+                let mut parts = line.split(['\t', ' ']).filter(|p| !p.is_empty());
+                if let (Some(from), Some(to)) = (parts.next(), parts.next())
+                    && let (Some(from), Some(to)) = (single_char(from), single_char(to)) {
+                    dictionaries.t2s.insert(from, to);
+                }
+            }
+        }
+        if let Some(lines) = read_lines(conf.corrections_path.as_deref()) {
+            for line in lines {
+                if let Some((from, to)) = line.split_once('\t')
+                    && !from.trim().is_empty() {
+                    dictionaries.corrections.push((from.trim().to_lowercase(), to.trim().to_lowercase()));
+                }
+            }
+            dictionaries.corrections.sort_by_key(|(from, _)| std::cmp::Reverse(from.chars().count()));
+        }
+        if let Some(lines) = read_lines(conf.synonyms_path.as_deref()) {
+            for line in lines {
+                let group: Vec<String> = line.split([',', '，'])
+                    .map(|term| term.trim().to_lowercase())
+                    .filter(|term| !term.is_empty())
+                    .collect();
+                if group.len() > 1 {
+                    dictionaries.synonyms.push(group);
+                }
+            }
+        }
+        tracing::info!("[Dictionaries] loaded. t2s = {}, corrections = {}, synonyms = {}",
+            dictionaries.t2s.len(), dictionaries.corrections.len(), dictionaries.synonyms.len());
+        dictionaries
+    }
+}
+
+pub fn read_lines(path: Option<&str>) -> Option<Vec<String>> {
+    let path = path?;
+    match fs::read_to_string(path) {
+        Ok(content) => Some(content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()),
+        Err(e) => {
+            tracing::error!("[read_lines] read dictionary err. path = {}, err = {}", path, e);
+            None
+        }
+    }
+}
+
+fn single_char(s: &str) -> Option<char> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+fn get_dictionaries() -> &'static Dictionaries {
+    DICTIONARIES.get_or_init(Dictionaries::load)
+}
+
+/// Full-width to half-width, lowercase, traditional to simplified, whitespace collapsed.
+pub fn normalize(query: &str) -> String {
+    let t2s = &get_dictionaries().t2s;
+    let mapped: String = query.chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            _ => c,
+        })
+        .flat_map(char::to_lowercase)
+        .map(|c| t2s.get(&c).copied().unwrap_or(c))
+        .collect();
+    mapped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Replaces misspellings in one left to right pass, so a replacement is never corrected again.
+pub fn correct(query: &str) -> String {
+    correct_with(query, &get_dictionaries().corrections)
+}
+
+/// `corrections` sorted longest first, the first one matching at a position wins.
+fn correct_with(query: &str, corrections: &[(String, String)]) -> String {
+    let mut corrected = String::with_capacity(query.len());
+    let mut prev = None;
+    let mut skip_to = 0;
+    for (i, c) in query.char_indices() {
+        if i < skip_to {
+            continue;
+        }
+        let rest = &query[i..];
+        let matched = corrections.iter().find(|(from, _)| {
+            rest.starts_with(from.as_str()) && whole_token(prev, from, rest[from.len()..].chars().next())
+        });
+        match matched {
+            Some((from, to)) => {
+                corrected.push_str(to);
+                prev = from.chars().last();
+                skip_to = i + from.len();
+            }
+            None => {
+                corrected.push(c);
+                prev = Some(c);
+            }
+        }
+    }
+    corrected
+}
+
+/// Latin words and numbers only match whole, while every CJK character is a token of its own.
+fn whole_token(before: Option<char>, word: &str, after: Option<char>) -> bool {
+    let latin = |c: char| c.is_ascii_alphanumeric();
+    let open_start = word.chars().next().is_some_and(latin) && before.is_some_and(latin);
+    let open_end = word.chars().last().is_some_and(latin) && after.is_some_and(latin);
+    !open_start && !open_end
+}
+
+/// Appends synonyms of the terms the query contains, up to `max_synonyms`.
+pub fn expand(query: &str) -> String {
+    let max_synonyms = config::get().query.max_synonyms;
+    let mut expansions: Vec<&str> = Vec::new();
+    for group in &get_dictionaries().synonyms {
+        if !group.iter().any(|term| query.contains(term.as_str())) {
+            continue;
+        }
+        for term in group {
+            if expansions.len() >= max_synonyms {
+                break;
+            }
+            if !query.contains(term.as_str()) && !expansions.contains(&term.as_str()) {
+                expansions.push(term);
+            }
+        }
+    }
+    if expansions.is_empty() {
+        query.to_string()
+    } else {
+        format!("{} {}", query, expansions.join(" "))
+    }
+}
+
+/// Normalization and spelling correction, cheap enough to run on every request.
+pub fn process(query: &str) -> String {
+    correct(&normalize(query))
+}
+
+/// Asks the chat model for a retrieval friendly rewrite when enabled, keeping the input otherwise.
+/// Queries the keyword prefilter rejects, like gibberish or blocked terms, never reach the model.
+pub async fn rewrite(query: &str) -> Result<String> {
+    if !config::get().query.llm_rewrite {
+        return Ok(query.to_string());
+    }
+    if let Some(reason) = prefilter::reject_keyword(query) {
+        tracing::info!("[rewrite] rewrite skipped. query = {}, reason = {}", query, reason);
+        return Ok(query.to_string());
+    }
+    let rewritten = model::call_rewrite_model(query).await
+        .context("[rewrite] call_rewrite_model err.")?;
+    let rewritten = normalize(&rewritten);
+    if rewritten.is_empty() {
+        Ok(query.to_string())
+    } else {
+        Ok(rewritten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corrections(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut corrections: Vec<(String, String)> = pairs.iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect();
+        corrections.sort_by_key(|(from, _)| std::cmp::Reverse(from.chars().count()));
+        corrections
+    }
+
+    #[test]
+    fn replacements_do_not_cascade() {
+        let corrections = corrections(&[("aple", "apple"), ("apple", "fruit")]);
+        assert_eq!(correct_with("aple pie", &corrections), "apple pie");
+    }
+
+    #[test]
+    fn latin_corrections_match_whole_words() {
+        let corrections = corrections(&[("iphon", "iphone"), ("pro", "pro max")]);
+        assert_eq!(correct_with("iphon case", &corrections), "iphone case");
+        assert_eq!(correct_with("iphone product", &corrections), "iphone product");
+        assert_eq!(correct_with("iphon pro", &corrections), "iphone pro max");
+    }
+
+    #[test]
+    fn cjk_corrections_match_inside_queries() {
+        let corrections = corrections(&[("苹过", "苹果"), ("苹过手机", "苹果手机")]);
+        assert_eq!(correct_with("新款苹过手机壳", &corrections), "新款苹果手机壳");
+        assert_eq!(correct_with("苹过", &corrections), "苹果");
+    }
+
+    #[test]
+    fn normalize_folds_width_and_case() {
+        assert_eq!(normalize("ＩＰｈｏｎｅ\u{3000} 15 "), "iphone 15");
+    }
+}
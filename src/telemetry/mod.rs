@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use anyhow::{Context, Result};
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime;
+use opentelemetry_sdk::trace::span_processor_with_async_runtime::BatchSpanProcessor;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tonic::metadata::{KeyRef, MetadataMap};
+use tracing::Span;
+use tracing::level_filters::LevelFilter;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use crate::config;
+
+static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Reads W3C trace context from gRPC metadata.
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys()
+            .filter_map(|key| match key {
+                KeyRef::Ascii(key) => Some(key.as_str()),
+                KeyRef::Binary(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Installs the log subscriber, exporting spans over OTLP when an endpoint is configured.
+pub fn init() -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let conf = &config::get().telemetry;
+    let otel_layer = match &conf.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()
+                .context("[init] build otlp exporter err.")?;
+            let provider = SdkTracerProvider::builder()
+                .with_span_processor(BatchSpanProcessor::builder(exporter, runtime::Tokio).build())
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(conf.sample_ratio))))
+                .with_resource(Resource::builder().with_service_name(conf.service_name.clone()).build())
+                .build();
+            let tracer = provider.tracer(conf.service_name.clone());
+            let _ = TRACER_PROVIDER.set(provider);
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel_layer)
+        .try_init()
+        .context("[init] install subscriber err.")?;
+    if let Some(endpoint) = &conf.otlp_endpoint {
+        tracing::info!("[init] exporting traces to {}", endpoint);
+    }
+    Ok(())
+}
+
+/// Exports the spans still buffered.
+pub fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get()
+        && let Err(e) = provider.shutdown() {
+        tracing::error!("[shutdown] tracer provider shutdown err. err = {:?}", e);
+    }
+}
+
+/// Span of one rpc, continuing the caller's trace from its `traceparent` metadata.
+pub fn rpc_span(method: &str, namespace: &str, metadata: &MetadataMap) -> Span {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)));
+    // rpc.grpc.status_code is recorded for failed rpcs, which still answer with an ok grpc status
+    let span = tracing::info_span!("rpc", otel.name = method, otel.kind = "server", namespace, rpc.grpc.status_code = tracing::field::Empty);
+    span.set_parent(parent);
+    span
+}
+
+/// Span of one consumed message, continuing the producer's trace from its properties.
+pub fn message_span(source: &str, properties: &HashMap<String, String>) -> Span {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(properties));
+    let span = tracing::info_span!("message", otel.name = source, otel.kind = "consumer");
+    span.set_parent(parent);
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::TraceContextExt;
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn extractor_reads_ascii_metadata_only() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("traceparent", TRACEPARENT.parse().unwrap());
+        metadata.insert_bin("trace-bin", tonic::metadata::MetadataValue::from_bytes(b"raw"));
+        let extractor = MetadataExtractor(&metadata);
+        assert_eq!(extractor.get("traceparent"), Some(TRACEPARENT));
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+    }
+
+    #[test]
+    fn traceparent_metadata_becomes_the_remote_parent() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("traceparent", TRACEPARENT.parse().unwrap());
+        let context = TraceContextPropagator::new().extract(&MetadataExtractor(&metadata));
+        let span = context.span();
+        let parent = span.span_context();
+        assert!(parent.is_remote());
+        assert_eq!(parent.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent.span_id().to_string(), "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn message_properties_carry_the_trace_too() {
+        let properties = HashMap::from([("traceparent".to_string(), TRACEPARENT.to_string())]);
+        let context = TraceContextPropagator::new().extract(&properties);
+        assert_eq!(context.span().span_context().trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    }
+}
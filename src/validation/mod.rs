@@ -0,0 +1,190 @@
+use std::fmt::Display;
+use crate::config::{self, ValidationConfig};
+use crate::error::ServiceError;
+use crate::recommend::{RecommendRequest, Scene, SearchClickReport, SearchRequest, SimilarItemsRequest, SuggestRequest};
+
+/// Checks an rpc input before any handler or DAL sees it.
+pub trait Validate {
+    fn rules(&self, v: &mut Validator);
+
+    /// All violated rules at once, as `Param_Error` naming each field.
+    fn validate(&self) -> Result<(), ServiceError> {
+        let mut v = Validator { conf: &config::get().validation, violations: Vec::new() };
+        self.rules(&mut v);
+        if v.violations.is_empty() {
+            return Ok(());
+        }
+        Err(ServiceError::Param(format!("invalid request: {}", v.violations.join("; "))))
+    }
+}
+
+pub fn well_formed_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && namespace.len() <= 64
+        && namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub struct Validator {
+    conf: &'static ValidationConfig,
+    violations: Vec<String>,
+}
+
+impl Validator {
+    fn check(&mut self, ok: bool, field: &str, message: impl FnOnce() -> String) -> &mut Self {
+        if !ok {
+            self.violations.push(format!("{} {}", field, message()));
+        }
+        self
+    }
+
+    fn namespace(&mut self, field: &str, namespace: &str) -> &mut Self {
+        if !well_formed_namespace(namespace) {
+            return self.check(false, field, || "must be 1 to 64 of [A-Za-z0-9_-]".to_string());
+        }
+        let registered = &self.conf.namespaces;
+        self.check(registered.is_empty() || registered.iter().any(|ns| ns == namespace), field,
+            || format!("{} is not registered", namespace))
+    }
+
+    fn range<T: PartialOrd + Display>(&mut self, field: &str, value: T, min: T, max: T) -> &mut Self {
+        let ok = value >= min && value <= max;
+        self.check(ok, field, || format!("must be in [{}, {}], got {}", min, max, value))
+    }
+
+    fn positive(&mut self, field: &str, value: i64) -> &mut Self {
+        self.check(value > 0, field, || format!("must be positive, got {}", value))
+    }
+
+    fn text(&mut self, field: &str, value: &str, min_chars: usize, max_chars: usize) -> &mut Self {
+        let chars = value.trim().chars().count();
+        self.check(chars >= min_chars && chars <= max_chars, field,
+            || format!("must be {} to {} characters, got {}", min_chars, max_chars, chars));
+        self.check(!value.chars().any(char::is_control), field, || "must not contain control characters".to_string())
+    }
+
+    fn max_len(&mut self, field: &str, len: usize, max: usize) -> &mut Self {
+        self.check(len <= max, field, || format!("must have at most {} entries, got {}", max, len))
+    }
+}
+
+impl Validate for RecommendRequest {
+    fn rules(&self, v: &mut Validator) {
+        let conf = v.conf;
+        let scene = Scene::try_from(self.scene).unwrap_or(Scene::Home);
+        let needs_item = matches!(scene, Scene::Detail | Scene::AfterPurchase);
+        v.namespace("namespace", &self.namespace)
+            .positive("user_id", self.user_id)
+            .range("page_size", self.page_size, 0, conf.max_page_size)
+            .max_len("cursor", self.cursor.len(), conf.max_cursor_len)
+            .max_len("exclude_ids", self.exclude_ids.len(), conf.max_exclude_ids)
+            .check(!needs_item || self.item_id > 0, "item_id", || format!("is required by scene {:?}", scene));
+    }
+}
+
+impl Validate for SearchRequest {
+    fn rules(&self, v: &mut Validator) {
+        let conf = v.conf;
+        v.namespace("namespace", &self.namespace)
+            .text("keyword", &self.keyword, 1, conf.max_keyword_chars)
+            .range("page", self.page, 1, conf.max_page)
+            .range("user_id", self.user_id, 0, i64::MAX);
+    }
+}
+
+impl Validate for SimilarItemsRequest {
+    fn rules(&self, v: &mut Validator) {
+        v.namespace("namespace", &self.namespace)
+            .positive("item_id", self.item_id)
+            .range("limit", self.limit, 0, i32::MAX);
+    }
+}
+
+impl Validate for SuggestRequest {
+    fn rules(&self, v: &mut Validator) {
+        let conf = v.conf;
+        v.namespace("namespace", &self.namespace)
+            .text("prefix", &self.prefix, 0, conf.max_prefix_chars)
+            .range("limit", self.limit, 0, i32::MAX);
+    }
+}
+
+impl Validate for SearchClickReport {
+    fn rules(&self, v: &mut Validator) {
+        v.namespace("namespace", &self.namespace)
+            .check(!self.request_id.is_empty() && self.request_id.len() <= 64, "request_id", || "must be 1 to 64 bytes".to_string())
+            .positive("item_id", self.item_id)
+            .range("position", self.position, 1, i32::MAX)
+            .range("user_id", self.user_id, 0, i64::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(request: &impl Validate) -> String {
+        match request.validate() {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn search() -> SearchRequest {
+        SearchRequest { namespace: "shop".to_string(), keyword: "phone".to_string(), page: 1, ..Default::default() }
+    }
+
+    #[test]
+    fn valid_search_passes() {
+        assert!(search().validate().is_ok());
+    }
+
+    #[test]
+    fn namespace_must_be_well_formed() {
+        for namespace in ["", "a b", "shop:1", &"a".repeat(65)] {
+            let request = SearchRequest { namespace: namespace.to_string(), ..search() };
+            assert!(violations(&request).contains("namespace must be 1 to 64"), "{:?}", namespace);
+        }
+    }
+
+    #[test]
+    fn every_violation_is_reported_at_once() {
+        let request = SearchRequest { keyword: " ".to_string(), page: 0, user_id: -1, ..search() };
+        let message = violations(&request);
+        assert!(message.contains("keyword must be 1 to 100 characters"), "{}", message);
+        assert!(message.contains("page must be in [1, 100], got 0"), "{}", message);
+        assert!(message.contains("user_id must be in"), "{}", message);
+        assert!(matches!(request.validate(), Err(ServiceError::Param(_))));
+    }
+
+    #[test]
+    fn keyword_rejects_control_characters() {
+        let request = SearchRequest { keyword: "pho\u{7}ne".to_string(), ..search() };
+        assert!(violations(&request).contains("control characters"));
+    }
+
+    #[test]
+    fn detail_scene_needs_an_item() {
+        let request = RecommendRequest {
+            namespace: "shop".to_string(),
+            user_id: 1,
+            scene: Scene::Detail as i32,
+            ..Default::default()
+        };
+        assert!(violations(&request).contains("item_id is required by scene Detail"));
+        assert!(RecommendRequest { item_id: 9, ..request }.validate().is_ok());
+    }
+
+    #[test]
+    fn click_needs_request_id_and_position() {
+        let report = SearchClickReport { namespace: "shop".to_string(), item_id: 3, ..Default::default() };
+        let message = violations(&report);
+        assert!(message.contains("request_id must be 1 to 64 bytes"), "{}", message);
+        assert!(message.contains("position must be in [1,"), "{}", message);
+    }
+
+    #[test]
+    fn empty_suggest_prefix_is_allowed() {
+        let request = SuggestRequest { namespace: "shop".to_string(), ..Default::default() };
+        assert!(request.validate().is_ok());
+    }
+}